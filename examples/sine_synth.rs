@@ -1,6 +1,6 @@
 //! Simple monophonic synthesizer generating a sine wave for each received MIDI note.
 
-use audio_midi_shell::{AudioGenerator, AudioMidiShell};
+use audio_midi_shell::{AudioGenerator, AudioMidiShell, InitContext, MidiMessage};
 
 const SAMPLE_RATE: u32 = 44100;
 const BLOCK_SIZE: usize = 1024;
@@ -11,13 +11,16 @@ fn main() -> ! {
 
 #[derive(Debug, Default)]
 struct SineSynth {
+    sample_rate: f32,
     level: f32,
     phase: f32,
     phase_inc: f32,
 }
 
 impl AudioGenerator for SineSynth {
-    fn init(&mut self, _block_size: usize) {}
+    fn init_with_context(&mut self, context: &InitContext) {
+        self.sample_rate = context.sample_rate as f32;
+    }
 
     fn process(&mut self, samples_left: &mut [f32], samples_right: &mut [f32]) {
         for (sample_left, sample_right) in samples_left.iter_mut().zip(samples_right.iter_mut()) {
@@ -33,17 +36,15 @@ impl AudioGenerator for SineSynth {
         }
     }
 
-    fn process_midi(&mut self, message: Vec<u8>) {
-        match message[0] & 0xF0 {
-            0x80 => {
-                // Note off
+    fn process_midi_message(&mut self, message: MidiMessage) {
+        match message {
+            MidiMessage::NoteOff { .. } => {
                 self.level = 0.0;
             }
-            0x90 => {
-                // Note on
-                self.level = message[2] as f32 / 127.0;
-                let frequency = 440.0 * f32::powf(2.0, (message[1] as i32 - 69) as f32 / 12.0);
-                self.phase_inc = frequency / SAMPLE_RATE as f32 * core::f32::consts::TAU;
+            MidiMessage::NoteOn { note, velocity, .. } => {
+                self.level = velocity as f32 / 127.0;
+                let frequency = 440.0 * f32::powf(2.0, (note as i32 - 69) as f32 / 12.0);
+                self.phase_inc = frequency / self.sample_rate * core::f32::consts::TAU;
             }
             _ => {}
         };
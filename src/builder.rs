@@ -0,0 +1,448 @@
+//! Builder for the shell configuration.
+
+use std::sync::mpsc;
+use std::time::{Duration, Instant};
+
+use tinyaudio::{run_output_device, OutputDeviceParameters};
+
+use crate::command::{self, WithCommands, COMMAND_QUEUE_SIZE};
+use crate::engine::Engine;
+use crate::fade;
+use crate::input::InputSource;
+use crate::midi::{self, BlockClock, MidiOutputForwarder, MIDI_QUEUE_SIZE};
+use crate::midi_input::MidiInputs;
+use crate::output;
+use crate::protection;
+use crate::recording;
+use crate::stats;
+use crate::swap::{self, EngineConfig, IntoAny};
+use crate::{
+    input, AudioMidiShell, AudioProcessor, CommandHandler, CommandSender, InitContext,
+    MidiPortFilter, OutputProtection, ShellError, WavInput, MAX_CHANNELS,
+};
+
+/// Builder for an [`AudioMidiShell`] with custom configuration.
+///
+/// ```no_run
+/// use audio_midi_shell::{AudioMidiShellBuilder, AudioProcessor, MidiPortFilter, PortMatcher};
+///
+/// struct Quad;
+///
+/// impl AudioProcessor for Quad {
+///     fn process(&mut self, _inputs: &[&[f32]], outputs: &mut [&mut [f32]]) {
+///         // Fill the four output buffers.
+///     }
+/// }
+///
+/// let shell = AudioMidiShellBuilder::new()
+///     .sample_rate(48000)
+///     .block_size(256)
+///     .output_channels(4)
+///     .midi_filter(MidiPortFilter::default().exclude(PortMatcher::Contains("Through".into())))
+///     .spawn(Quad)
+///     .unwrap();
+/// ```
+#[derive(Debug, Clone)]
+pub struct AudioMidiShellBuilder {
+    /// Sampling frequency in Hz.
+    sample_rate: u32,
+
+    /// Number of samples for the `process` function.
+    block_size: usize,
+
+    /// Number of frames requested from the audio device per callback, `None` for the block size.
+    device_buffer_size: Option<usize>,
+
+    /// Number of output channels.
+    output_channels: usize,
+
+    /// Number of input channels.
+    input_channels: usize,
+
+    /// File played as input signal instead of opening the input device.
+    input_file: Option<WavInput>,
+
+    /// Flag for connecting MIDI ports.
+    midi_enabled: bool,
+
+    /// Filter for the MIDI input ports.
+    midi_filter: MidiPortFilter,
+
+    /// Interval for rescanning the MIDI input ports.
+    midi_rescan_interval: Option<Duration>,
+
+    /// Number of MIDI events that can be queued in each direction.
+    midi_queue_size: usize,
+
+    /// Number of commands that can be queued for the processor.
+    command_queue_size: usize,
+
+    /// Flag for splitting the blocks at the frame offsets of MIDI events.
+    split_at_midi_events: bool,
+
+    /// Length of the fade-out when the shell is stopped.
+    fade_out_time: Duration,
+
+    /// Protection applied to the output, `None` if disabled.
+    output_protection: Option<OutputProtection>,
+}
+
+impl Default for AudioMidiShellBuilder {
+    fn default() -> Self {
+        Self {
+            sample_rate: 44100,
+            block_size: 1024,
+            device_buffer_size: None,
+            output_channels: 2,
+            input_channels: 0,
+            input_file: None,
+            midi_enabled: true,
+            midi_filter: MidiPortFilter::default(),
+            midi_rescan_interval: Some(Duration::from_secs(1)),
+            midi_queue_size: MIDI_QUEUE_SIZE,
+            command_queue_size: COMMAND_QUEUE_SIZE,
+            split_at_midi_events: false,
+            fade_out_time: Duration::from_millis(20),
+            output_protection: None,
+        }
+    }
+}
+
+impl AudioMidiShellBuilder {
+    /// Returns a builder with the default configuration: 44100 Hz, 1024 samples per block,
+    /// stereo output, no input and all MIDI input ports.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets the sampling frequency in Hz. Spawning fails with
+    /// [`ShellError::UnsupportedSampleRate`] if the output device doesn't support it.
+    pub fn sample_rate(mut self, sample_rate: u32) -> Self {
+        self.sample_rate = sample_rate;
+        self
+    }
+
+    /// Sets the number of samples for the `process` function.
+    /// The processor always receives blocks of this size, whatever buffer size the audio device
+    /// uses. Spawning fails with [`ShellError::UnsupportedBlockSize`] if it's `0`.
+    pub fn block_size(mut self, block_size: usize) -> Self {
+        self.block_size = block_size;
+        self
+    }
+
+    /// Sets the number of frames requested from the audio device per callback, `None` to request
+    /// the block size. Smaller buffers reduce the latency if the processor uses larger blocks.
+    /// The device may choose a different size. Spawning fails with
+    /// [`ShellError::UnsupportedBlockSize`] if it's `0`.
+    pub fn device_buffer_size(mut self, size: Option<usize>) -> Self {
+        self.device_buffer_size = size;
+        self
+    }
+
+    /// Sets the number of output channels, e.g. `1` for mono or `6` for 5.1.
+    /// Limited to [`MAX_CHANNELS`].
+    pub fn output_channels(mut self, channels: usize) -> Self {
+        self.output_channels = channels;
+        self
+    }
+
+    /// Sets the number of input channels, `0` to not open the input device unless an input file
+    /// is set.
+    /// Limited to [`MAX_CHANNELS`].
+    pub fn input_channels(mut self, channels: usize) -> Self {
+        self.input_channels = channels;
+        self
+    }
+
+    /// Sets a WAV file played as input signal instead of opening the input device.
+    /// Without input channels set, the channel count of the file is used.
+    pub fn input_file(mut self, file: WavInput) -> Self {
+        self.input_file = Some(file);
+        self
+    }
+
+    /// Enables or disables MIDI. If disabled, no MIDI ports are connected.
+    pub fn midi_enabled(mut self, enabled: bool) -> Self {
+        self.midi_enabled = enabled;
+        self
+    }
+
+    /// Sets the filter selecting the MIDI input ports to connect.
+    pub fn midi_filter(mut self, filter: MidiPortFilter) -> Self {
+        self.midi_filter = filter;
+        self
+    }
+
+    /// Sets the interval for rescanning the MIDI input ports, `None` to only scan on startup.
+    pub fn midi_rescan_interval(mut self, interval: Option<Duration>) -> Self {
+        self.midi_rescan_interval = interval;
+        self
+    }
+
+    /// Sets the number of MIDI events that can be queued between the MIDI ports and the
+    /// audio callback in each direction.
+    pub fn midi_queue_size(mut self, size: usize) -> Self {
+        self.midi_queue_size = size;
+        self
+    }
+
+    /// Sets the number of commands that can be queued for the processor.
+    /// Only used by [`AudioMidiShellBuilder::spawn_with_commands`].
+    pub fn command_queue_size(mut self, size: usize) -> Self {
+        self.command_queue_size = size;
+        self
+    }
+
+    /// Enables or disables splitting the blocks at the frame offsets of incoming MIDI events.
+    /// If enabled, `process` is called for the part of the block before each event and the event
+    /// is passed to the processor in between, so MIDI is handled sample-accurately without
+    /// evaluating the frame offsets. The events then have a frame offset of `0`.
+    pub fn split_at_midi_events(mut self, enabled: bool) -> Self {
+        self.split_at_midi_events = enabled;
+        self
+    }
+
+    /// Sets the length of the fade-out applied by [`AudioMidiShell::stop`] to avoid clicks.
+    pub fn fade_out_time(mut self, time: Duration) -> Self {
+        self.fade_out_time = time;
+        self
+    }
+
+    /// Enables the protection of the output against invalid samples and excessive levels.
+    /// The detected problems are reported via [`AudioMidiShell::protection_events`].
+    pub fn output_protection(mut self, protection: OutputProtection) -> Self {
+        self.output_protection = Some(protection);
+        self
+    }
+
+    /// Like [`AudioMidiShellBuilder::spawn`], but additionally opens a queue for the commands of
+    /// the processor and returns the handle for sending them. The commands are passed to
+    /// `process_command` in the audio callback before the next block is processed.
+    pub fn spawn_with_commands<P>(
+        self,
+        processor: P,
+    ) -> Result<(AudioMidiShell, CommandSender<P::Command>), ShellError>
+    where
+        P: AudioProcessor + CommandHandler + Send + 'static,
+    {
+        let (sender, consumer) = command::command_queue::<P::Command>(self.command_queue_size);
+        let mut shell = self
+            .spawn_returning(WithCommands::new(processor, consumer), |processor| {
+                Box::new(processor.into_inner())
+            })?;
+        shell.commands = Some(Box::new(sender.clone()));
+        Ok((shell, sender))
+    }
+
+    /// Initializes the MIDI ports and the audio devices and runs the processor in a callback.
+    /// It returns a shell object that must be kept alive.
+    ///
+    /// Returns an error if the sample rate or the channel counts are not supported or an audio
+    /// device can't be opened. MIDI errors are not fatal. If MIDI is unavailable, the shell runs
+    /// audio only. The errors are reported via [`AudioMidiShell::midi_port_events`].
+    pub fn spawn(
+        self,
+        processor: impl AudioProcessor + Send + 'static,
+    ) -> Result<AudioMidiShell, ShellError> {
+        self.spawn_returning(processor, |processor| Box::new(processor))
+    }
+
+    /// Spawns the shell, which hands back the processor converted by `into_any` when stopped.
+    fn spawn_returning<P>(
+        self,
+        processor: P,
+        into_any: IntoAny<P>,
+    ) -> Result<AudioMidiShell, ShellError>
+    where
+        P: AudioProcessor + Send + 'static,
+    {
+        let Self {
+            sample_rate,
+            block_size,
+            output_channels,
+            input_channels,
+            input_file,
+            ..
+        } = self;
+        let input_channels = match &input_file {
+            Some(file) if input_channels == 0 => file.channels(),
+            _ => input_channels,
+        };
+
+        if sample_rate == 0 {
+            return Err(ShellError::UnsupportedSampleRate(sample_rate));
+        }
+        if block_size == 0 {
+            return Err(ShellError::UnsupportedBlockSize(block_size));
+        }
+        if self.device_buffer_size == Some(0) {
+            return Err(ShellError::UnsupportedBlockSize(0));
+        }
+        if !(1..=MAX_CHANNELS).contains(&output_channels) {
+            return Err(ShellError::UnsupportedChannels(output_channels));
+        }
+        if input_channels > MAX_CHANNELS {
+            return Err(ShellError::UnsupportedChannels(input_channels));
+        }
+        output::check_sample_rate(sample_rate, output_channels)?;
+
+        let start = Instant::now();
+        let (midi_producer, mut midi_consumer) = midi::midi_queue(self.midi_queue_size);
+        let midi_inputs = self.midi_enabled.then(|| {
+            MidiInputs::new(
+                midi_producer,
+                start,
+                self.midi_filter,
+                self.midi_rescan_interval,
+            )
+        });
+        let mut block_clock = BlockClock::new(start, sample_rate, block_size);
+
+        let (input_device, mut input_source) = match input_file {
+            _ if input_channels == 0 => (None, None),
+            Some(mut file) => {
+                file.resample(sample_rate);
+                (None, Some(InputSource::File(file)))
+            }
+            None => {
+                let (producer, consumer) = input::input_buffer(block_size, input_channels);
+                let device = input::run_input_device(sample_rate, input_channels, producer)
+                    .map_err(|error| ShellError::AudioInput(error.to_string()))?;
+                (Some(device), Some(InputSource::Device(consumer)))
+            }
+        };
+
+        let (midi_sender, midi_output_consumer) = midi::midi_output_queue(self.midi_queue_size);
+        let midi_output = MidiOutputForwarder::new(midi_output_consumer);
+
+        let context = InitContext::new(sample_rate, block_size, output_channels, input_channels);
+        let mut engine = Engine::new(swap::boxed(processor, into_any), &context, midi_sender);
+        if self.split_at_midi_events {
+            engine.split_at_midi_events(self.midi_queue_size);
+        }
+
+        let params = engine.params().to_vec();
+        let (processor_sender, processor_receiver) = mpsc::channel();
+        let config = EngineConfig {
+            context,
+            midi_queue_size: self.midi_queue_size,
+            command_queue_size: self.command_queue_size,
+            split_at_midi_events: self.split_at_midi_events,
+        };
+        let (swap, mut engine) = swap::engine_switch(engine, processor_sender, config);
+
+        let device_buffer_size = self.device_buffer_size.unwrap_or(block_size);
+        let (fade_out, mut fade_out_ramp) = fade::fade_out(
+            sample_rate,
+            self.fade_out_time,
+            device_buffer_size.max(block_size),
+        );
+
+        let block_duration = Duration::from_secs_f64(block_size as f64 / sample_rate as f64);
+        let (stats, stats_recorder) = stats::callback_stats(block_duration, block_size);
+
+        let (protection_events, mut protector) = self
+            .output_protection
+            .map(|config| protection::output_protection(config, sample_rate))
+            .unzip();
+
+        let (recorder, mut recording_tap) = recording::recording(sample_rate);
+
+        let device_params = OutputDeviceParameters {
+            channels_count: output_channels,
+            sample_rate: sample_rate as usize,
+            channel_sample_count: device_buffer_size,
+        };
+
+        // Frames of the last processed block that were already written to the device. The output
+        // buffers of the engine act as FIFO between the processor and the device buffers.
+        let mut block_position = block_size;
+
+        let output_device = run_output_device(device_params, move |data| {
+            let callback_start = Instant::now();
+            fade_out_ramp.begin_block();
+            block_clock.begin_callback(data.len() / output_channels);
+
+            for (frame_no, samples) in data.chunks_mut(output_channels).enumerate() {
+                if block_position == block_size {
+                    engine.begin_block();
+                    block_clock.begin_block(frame_no);
+
+                    while let Ok((time, _)) = midi_consumer.peek() {
+                        if !block_clock.is_due(*time) {
+                            break;
+                        }
+                        if let Ok((time, event)) = midi_consumer.pop() {
+                            engine.process_midi_event(event.with_frame(block_clock.frame(time)));
+                        }
+                    }
+
+                    if let Some(input_source) = input_source.as_mut() {
+                        engine.read_input(input_source);
+                    }
+
+                    engine.process();
+
+                    if let Some(protector) = protector.as_mut() {
+                        protector.process(engine.outputs_mut());
+                    }
+
+                    block_position = 0;
+                }
+
+                let gain = fade_out_ramp.next_gain();
+                for (sample, output) in samples.iter_mut().zip(engine.outputs()) {
+                    *sample = output[block_position] * gain;
+                }
+                block_position += 1;
+            }
+
+            recording_tap.write(data, output_channels);
+
+            fade_out_ramp.end_block();
+            stats_recorder.record(callback_start.elapsed(), data.len() / output_channels);
+        })
+        .map_err(|error| ShellError::AudioOutput(error.to_string()))?;
+
+        Ok(AudioMidiShell {
+            output_device,
+            input_device,
+            midi_output,
+            midi_inputs,
+            params,
+            commands: None,
+            processor: processor_receiver,
+            fade_out,
+            swap,
+            stats,
+            protection_events,
+            recorder,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Processor producing silence.
+    struct Silence;
+
+    impl AudioProcessor for Silence {
+        fn process(&mut self, _: &[&[f32]], _: &mut [&mut [f32]]) {}
+    }
+
+    #[test]
+    fn rejects_zero_sizes_before_opening_devices() {
+        let builder = AudioMidiShellBuilder::new().midi_enabled(false);
+
+        assert!(matches!(
+            builder.clone().block_size(0).spawn(Silence),
+            Err(ShellError::UnsupportedBlockSize(0))
+        ));
+        assert!(matches!(
+            builder.device_buffer_size(Some(0)).spawn(Silence),
+            Err(ShellError::UnsupportedBlockSize(0))
+        ));
+    }
+}
@@ -0,0 +1,181 @@
+//! Commands sent from the application to the processor.
+
+use std::sync::{Arc, Mutex};
+
+use rtrb::{Consumer, Producer, RingBuffer};
+
+use crate::{AudioProcessor, InitContext, MidiEvent, MidiMessage, MidiSender, Param};
+
+/// Default number of commands that can be queued for the audio callback.
+pub(crate) const COMMAND_QUEUE_SIZE: usize = 256;
+
+/// Trait to be implemented by generators and processors that receive commands from the
+/// application via a [`CommandSender`].
+///
+/// ```no_run
+/// use audio_midi_shell::{AudioGenerator, AudioMidiShell, CommandHandler};
+///
+/// enum Command {
+///     SetFrequency(f32),
+///     Mute,
+/// }
+///
+/// struct Osc {
+///     frequency: f32,
+///     muted: bool,
+/// }
+///
+/// impl AudioGenerator for Osc {
+///     fn process(&mut self, samples_left: &mut [f32], samples_right: &mut [f32]) {
+///         // Render the oscillator.
+///     }
+/// }
+///
+/// impl CommandHandler for Osc {
+///     type Command = Command;
+///
+///     fn process_command(&mut self, command: Command) {
+///         match command {
+///             Command::SetFrequency(frequency) => self.frequency = frequency,
+///             Command::Mute => self.muted = true,
+///         }
+///     }
+/// }
+///
+/// let osc = Osc { frequency: 440.0, muted: false };
+/// let (shell, commands) = AudioMidiShell::builder().spawn_with_commands(osc).unwrap();
+/// commands.send(Command::SetFrequency(880.0)).ok();
+/// ```
+pub trait CommandHandler {
+    /// Type of the commands.
+    type Command: Send + 'static;
+
+    /// Processes a command. Called in the audio callback before `process`.
+    fn process_command(&mut self, command: Self::Command);
+}
+
+/// Creates the queue transporting commands from the application to the audio callback.
+pub(crate) fn command_queue<C>(size: usize) -> (CommandSender<C>, Consumer<C>) {
+    let (producer, consumer) = RingBuffer::<C>::new(size);
+    let sender = CommandSender {
+        producer: Arc::new(Mutex::new(producer)),
+    };
+
+    (sender, consumer)
+}
+
+/// Handle for sending commands to a processor spawned with
+/// [`AudioMidiShellBuilder::spawn_with_commands`](crate::AudioMidiShellBuilder::spawn_with_commands).
+///
+/// Sending doesn't block the audio callback. The handle can be cloned to send from several
+/// threads.
+pub struct CommandSender<C> {
+    /// Producer for the commands, the lock is only shared between senders, never with the audio
+    /// callback.
+    producer: Arc<Mutex<Producer<C>>>,
+}
+
+impl<C> Clone for CommandSender<C> {
+    fn clone(&self) -> Self {
+        Self {
+            producer: self.producer.clone(),
+        }
+    }
+}
+
+impl<C> CommandSender<C> {
+    /// Sends a command to the processor, which receives it via
+    /// [`CommandHandler::process_command`] before the next block is processed.
+    /// Returns the command if the queue is full or the processor was replaced.
+    pub fn send(&self, command: C) -> Result<(), C> {
+        let Ok(mut producer) = self.producer.lock() else {
+            return Err(command);
+        };
+
+        if producer.is_abandoned() {
+            return Err(command);
+        }
+
+        producer
+            .push(command)
+            .map_err(|rtrb::PushError::Full(command)| command)
+    }
+
+    /// Returns if the processor receiving the commands was replaced and released, so no more
+    /// commands can be sent.
+    pub fn is_closed(&self) -> bool {
+        self.producer
+            .lock()
+            .map_or(true, |producer| producer.is_abandoned())
+    }
+}
+
+/// Processor delivering the queued commands before each block.
+pub(crate) struct WithCommands<P: CommandHandler> {
+    /// The wrapped processor.
+    processor: P,
+
+    /// Consumer for the commands.
+    commands: Consumer<P::Command>,
+}
+
+impl<P: CommandHandler> WithCommands<P> {
+    /// Wraps `processor` receiving the commands from `commands`.
+    pub fn new(processor: P, commands: Consumer<P::Command>) -> Self {
+        Self {
+            processor,
+            commands,
+        }
+    }
+
+    /// Returns the wrapped processor.
+    pub fn into_inner(self) -> P {
+        self.processor
+    }
+}
+
+impl<P: AudioProcessor + CommandHandler> AudioProcessor for WithCommands<P> {
+    fn init(&mut self, block_size: usize) {
+        self.processor.init(block_size);
+    }
+
+    fn init_with_context(&mut self, context: &InitContext) {
+        self.processor.init_with_context(context);
+    }
+
+    fn process(&mut self, inputs: &[&[f32]], outputs: &mut [&mut [f32]]) {
+        while let Ok(command) = self.commands.pop() {
+            self.processor.process_command(command);
+        }
+
+        self.processor.process(inputs, outputs);
+    }
+
+    fn process_midi(&mut self, message: &[u8]) {
+        self.processor.process_midi(message);
+    }
+
+    fn process_midi_message(&mut self, message: MidiMessage) {
+        self.processor.process_midi_message(message);
+    }
+
+    fn process_midi_event(&mut self, event: MidiEvent) {
+        self.processor.process_midi_event(event);
+    }
+
+    fn init_midi_output(&mut self, sender: MidiSender) {
+        self.processor.init_midi_output(sender);
+    }
+
+    fn params(&self) -> Vec<Param> {
+        self.processor.params()
+    }
+
+    fn deinit(&mut self) {
+        self.processor.deinit();
+    }
+
+    fn min_output_channels(&self) -> usize {
+        self.processor.min_output_channels()
+    }
+}
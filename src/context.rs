@@ -0,0 +1,42 @@
+//! Settings passed to the processors on initialization.
+
+/// Settings of the shell passed to `init_with_context`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct InitContext {
+    /// Sampling frequency of the output device in Hz.
+    ///
+    /// The shell only starts if the device supports this rate, otherwise spawning fails with
+    /// [`ShellError::UnsupportedSampleRate`](crate::ShellError::UnsupportedSampleRate).
+    pub sample_rate: u32,
+
+    /// Maximum number of samples passed to the `process` function.
+    pub max_block_size: usize,
+
+    /// Number of output channels.
+    pub output_channels: usize,
+
+    /// Number of input channels.
+    pub input_channels: usize,
+
+    /// Version of the shell.
+    pub shell_version: &'static str,
+}
+
+impl InitContext {
+    /// Returns the context for the given settings.
+    pub(crate) fn new(
+        sample_rate: u32,
+        max_block_size: usize,
+        output_channels: usize,
+        input_channels: usize,
+    ) -> Self {
+        Self {
+            sample_rate,
+            max_block_size,
+            output_channels,
+            input_channels,
+            shell_version: env!("CARGO_PKG_VERSION"),
+        }
+    }
+}
@@ -0,0 +1,349 @@
+//! Processing engine shared by the live shell and the offline renderers.
+
+use std::ops::Range;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+use crate::{AudioProcessor, InitContext, MidiEvent, MidiMessage, MidiSender, Param};
+
+/// Maximum number of input or output channels.
+pub const MAX_CHANNELS: usize = 32;
+
+/// Wraps a processor together with its sample buffers.
+pub(crate) struct Engine<P> {
+    /// The processor.
+    processor: P,
+
+    /// Input buffers, one per channel.
+    inputs: Vec<Vec<f32>>,
+
+    /// Output buffers, at least one per channel.
+    outputs: Vec<Vec<f32>>,
+
+    /// Number of output channels.
+    output_channels: usize,
+
+    /// Parameters declared by the processor.
+    params: Vec<Param>,
+
+    /// MIDI events held back until their frame offset, `None` if blocks aren't split.
+    split_events: Option<Vec<MidiEvent>>,
+
+    /// Start of the processed part of the block, added to the frame offsets of the MIDI
+    /// messages sent by the processor.
+    midi_frame_offset: Arc<AtomicUsize>,
+}
+
+impl<P: AudioProcessor> Engine<P> {
+    /// Initializes the processor and allocates the buffers.
+    /// - `context` is passed to the processor, its channel counts must not exceed
+    ///   [`MAX_CHANNELS`].
+    /// - `midi_sender` is passed to the processor for sending MIDI messages.
+    pub fn new(mut processor: P, context: &InitContext, midi_sender: MidiSender) -> Self {
+        let block_size = context.max_block_size;
+        let output_channels = context.output_channels;
+        let input_channels = context.input_channels;
+
+        assert!(
+            (1..=MAX_CHANNELS).contains(&output_channels) && input_channels <= MAX_CHANNELS,
+            "Unsupported channel count"
+        );
+
+        let midi_frame_offset = midi_sender.frame_offset();
+        processor.init_with_context(context);
+        processor.init_midi_output(midi_sender);
+
+        let rendered_channels = output_channels.max(processor.min_output_channels());
+        let params = processor.params();
+
+        Self {
+            processor,
+            inputs: vec![vec![0.0; block_size]; input_channels],
+            outputs: vec![vec![0.0; block_size]; rendered_channels],
+            output_channels,
+            params,
+            split_events: None,
+            midi_frame_offset,
+        }
+    }
+
+    /// Enables splitting the blocks at the frame offsets of MIDI events. The events are passed
+    /// to the processor between partial `process` calls, each with a frame offset of `0`.
+    /// - `capacity` is the number of events held back per block. Once it's reached, the held
+    ///   events are passed on immediately together with the new one, preserving their order.
+    pub fn split_at_midi_events(&mut self, capacity: usize) {
+        self.split_events = Some(Vec::with_capacity(capacity));
+    }
+
+    /// Passes a MIDI event to the processor, or holds it back until its frame offset if blocks
+    /// are split.
+    pub fn process_midi_event(&mut self, event: MidiEvent) {
+        let Some(mut events) = self.split_events.take() else {
+            self.deliver_midi_event(event);
+            return;
+        };
+
+        if events.len() < events.capacity() {
+            events.push(event);
+        } else {
+            // Delivered at the start of the block, as later events must not overtake held ones.
+            for event in events.drain(..).chain(std::iter::once(event)) {
+                self.deliver_midi_event(event.with_frame(0));
+            }
+        }
+
+        self.split_events = Some(events);
+    }
+
+    /// Updates the parameters mapped to a received controller and passes a MIDI event to the
+    /// processor.
+    fn deliver_midi_event(&mut self, event: MidiEvent) {
+        if let Some(MidiMessage::ControlChange {
+            controller, value, ..
+        }) = event.parse()
+        {
+            for param in self.params.iter().filter(|p| p.cc() == Some(controller)) {
+                param.set_normalized(value as f32 / 127.0);
+            }
+        }
+
+        self.processor.process_midi_event(event);
+    }
+
+    /// Returns the parameters declared by the processor.
+    pub fn params(&self) -> &[Param] {
+        &self.params
+    }
+
+    /// Returns the input buffers of the last processed block.
+    pub fn inputs(&self) -> &[Vec<f32>] {
+        &self.inputs
+    }
+
+    /// Returns the input buffers to be filled before calling `process`.
+    pub fn inputs_mut(&mut self) -> &mut [Vec<f32>] {
+        &mut self.inputs
+    }
+
+    /// Processes a block of samples from the input buffers into the output buffers.
+    pub fn process(&mut self) {
+        for buffer in self.outputs.iter_mut() {
+            buffer.fill(0.0);
+        }
+
+        let block_size = self.outputs[0].len();
+        let mut start = 0;
+
+        // The events are taken out temporarily, so the buffer is reused without allocating.
+        if let Some(mut events) = self.split_events.take() {
+            for event in events.drain(..) {
+                let frame = event.frame().clamp(start, block_size);
+                if frame > start {
+                    self.process_range(start..frame);
+                    start = frame;
+                }
+                self.midi_frame_offset.store(frame, Ordering::Relaxed);
+                self.deliver_midi_event(event.with_frame(0));
+            }
+            self.split_events = Some(events);
+        }
+
+        self.process_range(start..block_size);
+        self.midi_frame_offset.store(0, Ordering::Relaxed);
+
+        if self.outputs.len() > self.output_channels {
+            self.mix_down();
+        }
+    }
+
+    /// Processes the frames in `range` of the buffers.
+    fn process_range(&mut self, range: Range<usize>) {
+        if range.is_empty() {
+            return;
+        }
+        self.midi_frame_offset.store(range.start, Ordering::Relaxed);
+
+        let input_count = self.inputs.len();
+        let output_count = self.outputs.len();
+
+        let mut inputs: [&[f32]; MAX_CHANNELS] = Default::default();
+        for (input, buffer) in inputs.iter_mut().zip(self.inputs.iter()) {
+            *input = &buffer[range.clone()];
+        }
+
+        let mut outputs: [&mut [f32]; MAX_CHANNELS] = Default::default();
+        for (output, buffer) in outputs.iter_mut().zip(self.outputs.iter_mut()) {
+            *output = &mut buffer[range.clone()];
+        }
+
+        self.processor
+            .process(&inputs[..input_count], &mut outputs[..output_count]);
+    }
+
+    /// Folds the additional buffers rendered by the processor into the output channels.
+    fn mix_down(&mut self) {
+        let (outputs, extra_outputs) = self.outputs.split_at_mut(self.output_channels);
+
+        for (channel_no, output) in outputs.iter_mut().enumerate() {
+            let mut source_count = 1;
+
+            for buffer in extra_outputs
+                .iter()
+                .skip(channel_no)
+                .step_by(self.output_channels)
+            {
+                for (sample, extra_sample) in output.iter_mut().zip(buffer.iter()) {
+                    *sample += extra_sample;
+                }
+                source_count += 1;
+            }
+
+            let gain = 1.0 / source_count as f32;
+            output.iter_mut().for_each(|sample| *sample *= gain);
+        }
+    }
+
+    /// Returns the output buffers of the last processed block, one per channel.
+    pub fn outputs(&self) -> &[Vec<f32>] {
+        &self.outputs[..self.output_channels]
+    }
+
+    /// Returns the output buffers of the last processed block for modification.
+    pub fn outputs_mut(&mut self) -> &mut [Vec<f32>] {
+        &mut self.outputs[..self.output_channels]
+    }
+
+    /// Returns a reference to the processor.
+    pub fn processor(&self) -> &P {
+        &self.processor
+    }
+
+    /// Returns a mutable reference to the processor.
+    pub fn processor_mut(&mut self) -> &mut P {
+        &mut self.processor
+    }
+
+    /// Deinitializes the processor and returns it.
+    pub fn into_processor(mut self) -> P {
+        self.processor.deinit();
+        self.processor
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::midi::midi_output_queue;
+    use crate::TestShell;
+
+    /// Call received by the [`Recorder`].
+    #[derive(Debug, PartialEq)]
+    enum Call {
+        /// `process` with the number of frames.
+        Process(usize),
+
+        /// `process_midi_event` with the frame offset and the note number.
+        Midi(usize, u8),
+    }
+
+    /// Processor recording its calls.
+    #[derive(Default)]
+    struct Recorder(Vec<Call>);
+
+    impl AudioProcessor for Recorder {
+        fn process(&mut self, _: &[&[f32]], outputs: &mut [&mut [f32]]) {
+            self.0.push(Call::Process(outputs[0].len()));
+        }
+
+        fn process_midi_event(&mut self, event: MidiEvent) {
+            self.0.push(Call::Midi(event.frame(), event.data()[1]));
+        }
+    }
+
+    /// Returns an engine with 64 frames per block, holding back up to `capacity` events.
+    fn split_engine(capacity: usize) -> Engine<Recorder> {
+        let (midi_sender, _) = midi_output_queue(16);
+        let context = InitContext::new(44100, 64, 2, 0);
+        let mut engine = Engine::new(Recorder::default(), &context, midi_sender);
+        engine.split_at_midi_events(capacity);
+        engine
+    }
+
+    /// Returns a note on event for `note` at `frame`.
+    fn note_on(frame: usize, note: u8) -> MidiEvent {
+        MidiEvent::new(frame, &[0x90, note, 100]).unwrap()
+    }
+
+    #[test]
+    fn splits_blocks_at_midi_events() {
+        let mut shell = TestShell::new(64, Recorder::default()).split_at_midi_events();
+        shell.schedule_midi(10, &[0x90, 60, 100]);
+        shell.schedule_midi(40, &[0x80, 60, 0]);
+        shell.schedule_midi(64, &[0x90, 62, 100]);
+        shell.run(2);
+
+        assert_eq!(
+            shell.generator().0,
+            [
+                Call::Process(10),
+                Call::Midi(0, 60),
+                Call::Process(30),
+                Call::Midi(0, 60),
+                Call::Process(24),
+                Call::Midi(0, 62),
+                Call::Process(64),
+            ]
+        );
+    }
+
+    #[test]
+    fn passes_midi_events_with_frame_offsets_without_splitting() {
+        let mut shell = TestShell::new(64, Recorder::default());
+        shell.schedule_midi(10, &[0x90, 60, 100]);
+        shell.run(1);
+
+        assert_eq!(shell.generator().0, [Call::Midi(10, 60), Call::Process(64)]);
+    }
+
+    #[test]
+    fn clamps_frame_offsets_to_the_block() {
+        let mut engine = split_engine(8);
+        engine.process_midi_event(note_on(20, 60));
+        engine.process_midi_event(note_on(10, 61));
+        engine.process_midi_event(note_on(100, 62));
+        engine.process();
+
+        assert_eq!(
+            engine.processor().0,
+            [
+                Call::Process(20),
+                Call::Midi(0, 60),
+                Call::Midi(0, 61),
+                Call::Process(44),
+                Call::Midi(0, 62),
+            ]
+        );
+    }
+
+    #[test]
+    fn keeps_the_order_of_events_exceeding_the_capacity() {
+        let mut engine = split_engine(2);
+        engine.process_midi_event(note_on(10, 60));
+        engine.process_midi_event(note_on(20, 61));
+        engine.process_midi_event(note_on(30, 62));
+        engine.process_midi_event(note_on(40, 63));
+        engine.process();
+
+        assert_eq!(
+            engine.processor().0,
+            [
+                Call::Midi(0, 60),
+                Call::Midi(0, 61),
+                Call::Midi(0, 62),
+                Call::Process(40),
+                Call::Midi(0, 63),
+                Call::Process(24),
+            ]
+        );
+    }
+}
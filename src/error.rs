@@ -0,0 +1,83 @@
+//! Error type of the shell.
+
+use std::fmt;
+
+/// Errors reported by the shell.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ShellError {
+    /// The audio output device could not be opened.
+    AudioOutput(String),
+
+    /// The audio input device could not be opened.
+    AudioInput(String),
+
+    /// The MIDI system could not be initialized.
+    MidiInit(String),
+
+    /// A MIDI port could not be connected.
+    MidiConnect {
+        /// Name of the port.
+        port: String,
+
+        /// Reason for the failure.
+        reason: String,
+    },
+
+    /// The requested number of channels is not supported.
+    UnsupportedChannels(usize),
+
+    /// The requested sample rate is not supported.
+    UnsupportedSampleRate(u32),
+
+    /// The requested block size or device buffer size is not supported.
+    UnsupportedBlockSize(usize),
+
+    /// The handler for termination signals could not be installed.
+    Signal(String),
+
+    /// A generator could not be loaded from a dynamic library.
+    Library(String),
+
+    /// The output could not be recorded.
+    Recording(String),
+
+    /// A file could not be loaded as input signal.
+    InputFile(String),
+
+    /// The rendered output could not be written to a file.
+    OutputFile(String),
+
+    /// The generator or processor was not handed back by the audio callback when the shell was
+    /// stopped.
+    ProcessorNotReturned,
+}
+
+impl fmt::Display for ShellError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::AudioOutput(reason) => write!(f, "Audio output error: {}", reason),
+            Self::AudioInput(reason) => write!(f, "Audio input error: {}", reason),
+            Self::MidiInit(reason) => write!(f, "MIDI initialization error: {}", reason),
+            Self::MidiConnect { port, reason } => {
+                write!(f, "Connecting to MIDI port {} failed: {}", port, reason)
+            }
+            Self::UnsupportedChannels(channels) => {
+                write!(f, "Unsupported number of channels: {}", channels)
+            }
+            Self::UnsupportedSampleRate(sample_rate) => {
+                write!(f, "Unsupported sample rate: {} Hz", sample_rate)
+            }
+            Self::UnsupportedBlockSize(block_size) => {
+                write!(f, "Unsupported block size: {}", block_size)
+            }
+            Self::Signal(reason) => write!(f, "Signal handler error: {}", reason),
+            Self::Library(reason) => write!(f, "Library loading error: {}", reason),
+            Self::Recording(reason) => write!(f, "Recording error: {}", reason),
+            Self::InputFile(reason) => write!(f, "Input file error: {}", reason),
+            Self::OutputFile(reason) => write!(f, "Output file error: {}", reason),
+            Self::ProcessorNotReturned => write!(f, "Processor not returned by the audio callback"),
+        }
+    }
+}
+
+impl std::error::Error for ShellError {}
@@ -0,0 +1,109 @@
+//! Fade-out of the output before the shell is stopped.
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// Number of callback periods waited for the fade-out in addition to its length.
+const TIMEOUT_MARGIN_CALLBACKS: u32 = 4;
+
+/// Creates the handles for triggering the fade-out and applying it in the audio callback.
+/// - `sample_rate` is the sampling frequency in Hz.
+/// - `time` is the length of the fade-out.
+/// - `callback_frames` is the largest number of frames written per audio callback.
+pub(crate) fn fade_out(
+    sample_rate: u32,
+    time: Duration,
+    callback_frames: usize,
+) -> (FadeOutTrigger, FadeOutRamp) {
+    let requested = Arc::new(AtomicBool::new(false));
+    let finished = Arc::new(AtomicBool::new(false));
+    let length = (time.as_secs_f64() * sample_rate as f64) as usize;
+    let callback_period = Duration::from_secs_f64(callback_frames as f64 / sample_rate as f64);
+
+    let trigger = FadeOutTrigger {
+        requested: requested.clone(),
+        finished: finished.clone(),
+        timeout: time + callback_period * TIMEOUT_MARGIN_CALLBACKS,
+    };
+    let ramp = FadeOutRamp {
+        requested,
+        finished,
+        gain: 1.0,
+        step: 1.0 / length.max(1) as f32,
+        active: false,
+    };
+
+    (trigger, ramp)
+}
+
+/// Handle for starting the fade-out from the main thread.
+pub(crate) struct FadeOutTrigger {
+    /// Flag set when the fade-out is requested.
+    requested: Arc<AtomicBool>,
+
+    /// Flag set by the audio callback once the output is silent.
+    finished: Arc<AtomicBool>,
+
+    /// Time to wait for the fade-out, its length plus a margin of a few callbacks.
+    timeout: Duration,
+}
+
+impl FadeOutTrigger {
+    /// Starts the fade-out and waits until the output is silent.
+    /// Returns `false` if the fade-out didn't finish in time, e.g. because the audio callback
+    /// isn't running.
+    pub fn fade_out(&self) -> bool {
+        self.requested.store(true, Ordering::Release);
+        let start = Instant::now();
+
+        while !self.finished.load(Ordering::Acquire) {
+            if start.elapsed() > self.timeout {
+                return false;
+            }
+            std::thread::sleep(Duration::from_millis(1));
+        }
+
+        true
+    }
+}
+
+/// Gain ramp applied to the output in the audio callback.
+pub(crate) struct FadeOutRamp {
+    /// Flag set when the fade-out is requested.
+    requested: Arc<AtomicBool>,
+
+    /// Flag set once the output is silent.
+    finished: Arc<AtomicBool>,
+
+    /// Current gain.
+    gain: f32,
+
+    /// Gain decrement per frame.
+    step: f32,
+
+    /// Flag for a running fade-out, updated at the start of each block.
+    active: bool,
+}
+
+impl FadeOutRamp {
+    /// Checks for a requested fade-out. Must be called at the start of each block.
+    pub fn begin_block(&mut self) {
+        self.active = self.requested.load(Ordering::Acquire);
+    }
+
+    /// Returns the gain for the next frame.
+    pub fn next_gain(&mut self) -> f32 {
+        if self.active {
+            self.gain = (self.gain - self.step).max(0.0);
+        }
+        self.gain
+    }
+
+    /// Signals a finished fade-out. Must be called at the end of each block.
+    pub fn end_block(&mut self) {
+        if self.active && self.gain == 0.0 {
+            self.finished.store(true, Ordering::Release);
+        }
+    }
+}
@@ -0,0 +1,246 @@
+//! WAV files as input signal source.
+
+use std::fmt;
+use std::path::Path;
+
+use crate::ShellError;
+
+/// Input signal read from a WAV file instead of the input device.
+///
+/// The file is loaded into memory completely and resampled with linear interpolation if its
+/// sample rate differs from the one of the shell. It's played once or looped. Files with fewer
+/// channels than the processor inputs are repeated, e.g. a mono file feeds both stereo inputs.
+///
+/// ```no_run
+/// use audio_midi_shell::{AudioMidiShell, WavInput};
+/// # struct Delay;
+/// # impl audio_midi_shell::AudioProcessor for Delay {
+/// #     fn process(&mut self, _: &[&[f32]], _: &mut [&mut [f32]]) {}
+/// # }
+///
+/// let shell = AudioMidiShell::builder()
+///     .input_file(WavInput::open("drums.wav").unwrap().looping(true))
+///     .spawn(Delay)
+///     .unwrap();
+/// ```
+#[derive(Clone)]
+pub struct WavInput {
+    /// Samples of each channel.
+    channels: Vec<Vec<f32>>,
+
+    /// Sampling frequency of the samples in Hz.
+    sample_rate: u32,
+
+    /// Flag for restarting at the beginning once the end is reached.
+    looping: bool,
+
+    /// Frame position of the next read.
+    position: usize,
+}
+
+impl fmt::Debug for WavInput {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("WavInput")
+            .field("channels", &self.channels())
+            .field("frames", &self.frame_count())
+            .field("sample_rate", &self.sample_rate)
+            .field("looping", &self.looping)
+            .finish()
+    }
+}
+
+impl WavInput {
+    /// Loads the WAV file at `path`, played once by default.
+    /// Integer and float samples of any bit depth are supported.
+    pub fn open(path: impl AsRef<Path>) -> Result<Self, ShellError> {
+        let path = path.as_ref();
+        let error = |error: &dyn fmt::Display| {
+            ShellError::InputFile(format!("{}: {}", path.display(), error))
+        };
+
+        let mut reader = hound::WavReader::open(path).map_err(|e| error(&e))?;
+        let spec = reader.spec();
+        let channel_count = spec.channels as usize;
+        if channel_count == 0 {
+            return Err(error(&"No channels"));
+        }
+
+        let samples = match spec.sample_format {
+            hound::SampleFormat::Float => reader.samples::<f32>().collect::<Result<Vec<_>, _>>(),
+            hound::SampleFormat::Int => {
+                let scale = 1.0 / (1u32 << (spec.bits_per_sample - 1)) as f32;
+                reader
+                    .samples::<i32>()
+                    .map(|sample| sample.map(|sample| sample as f32 * scale))
+                    .collect()
+            }
+        }
+        .map_err(|e| error(&e))?;
+
+        let channels = (0..channel_count)
+            .map(|channel_no| {
+                samples
+                    .iter()
+                    .skip(channel_no)
+                    .step_by(channel_count)
+                    .copied()
+                    .collect()
+            })
+            .collect();
+
+        Ok(Self {
+            channels,
+            sample_rate: spec.sample_rate,
+            looping: false,
+            position: 0,
+        })
+    }
+
+    /// Returns the input restarting at the beginning once the end is reached, or staying silent
+    /// if `looping` is `false`.
+    pub fn looping(mut self, looping: bool) -> Self {
+        self.looping = looping;
+        self
+    }
+
+    /// Returns the number of channels of the file.
+    pub fn channels(&self) -> usize {
+        self.channels.len()
+    }
+
+    /// Returns the number of frames at the current sample rate.
+    pub fn frame_count(&self) -> usize {
+        self.channels.first().map_or(0, Vec::len)
+    }
+
+    /// Returns the sampling frequency in Hz, which is the one of the shell once the input is
+    /// passed to it.
+    pub fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    /// Converts the samples to `sample_rate` with linear interpolation.
+    pub(crate) fn resample(&mut self, sample_rate: u32) {
+        if sample_rate == self.sample_rate || sample_rate == 0 {
+            return;
+        }
+
+        let ratio = self.sample_rate as f64 / sample_rate as f64;
+        let frame_count = (self.frame_count() as f64 / ratio).round() as usize;
+        let looping = self.looping;
+
+        for samples in self.channels.iter_mut() {
+            *samples = (0..frame_count)
+                .map(|frame_no| {
+                    let position = frame_no as f64 * ratio;
+                    let index = position as usize;
+                    let fraction = (position - index as f64) as f32;
+                    let sample = samples.get(index).copied().unwrap_or(0.0);
+                    // Looped files continue with their first sample.
+                    let next_sample = samples
+                        .get(index + 1)
+                        .or_else(|| samples.first().filter(|_| looping))
+                        .copied()
+                        .unwrap_or(0.0);
+
+                    sample + (next_sample - sample) * fraction
+                })
+                .collect();
+        }
+
+        self.sample_rate = sample_rate;
+    }
+
+    /// Fills the input buffers with the next frames of the file.
+    pub(crate) fn read(&mut self, inputs: &mut [Vec<f32>]) {
+        let frame_count = self.frame_count();
+        let block_size = inputs.first().map_or(0, Vec::len);
+
+        for frame_no in 0..block_size {
+            if self.looping && self.position >= frame_count {
+                self.position = 0;
+            }
+
+            for (channel_no, input) in inputs.iter_mut().enumerate() {
+                let samples = &self.channels[channel_no % self.channels.len()];
+                input[frame_no] = samples.get(self.position).copied().unwrap_or(0.0);
+            }
+
+            self.position = (self.position + 1).min(frame_count);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Returns a mono input with the given samples at 22.05 kHz.
+    fn input(samples: &[f32], looping: bool) -> WavInput {
+        WavInput {
+            channels: vec![samples.to_vec()],
+            sample_rate: 22050,
+            looping,
+            position: 0,
+        }
+    }
+
+    #[test]
+    fn resamples_with_linear_interpolation() {
+        let mut wav = input(&[1.0, 2.0], false);
+        wav.resample(44100);
+
+        assert_eq!(wav.sample_rate(), 44100);
+        assert_eq!(wav.channels[0], [1.0, 1.5, 2.0, 1.0]);
+    }
+
+    #[test]
+    fn resamples_looped_files_towards_the_first_sample() {
+        let mut wav = input(&[1.0, 2.0], true);
+        wav.resample(44100);
+
+        assert_eq!(wav.channels[0], [1.0, 1.5, 2.0, 1.5]);
+    }
+
+    #[test]
+    fn keeps_the_samples_at_the_same_rate() {
+        let mut wav = input(&[1.0, 2.0, 3.0], false);
+        wav.resample(22050);
+        wav.resample(0);
+
+        assert_eq!(wav.channels[0], [1.0, 2.0, 3.0]);
+    }
+
+    #[test]
+    fn pads_with_silence_after_the_end() {
+        let mut wav = input(&[1.0, 2.0, 3.0], false);
+        let mut inputs = vec![vec![0.0; 2]; 1];
+
+        wav.read(&mut inputs);
+        assert_eq!(inputs[0], [1.0, 2.0]);
+        wav.read(&mut inputs);
+        assert_eq!(inputs[0], [3.0, 0.0]);
+        wav.read(&mut inputs);
+        assert_eq!(inputs[0], [0.0, 0.0]);
+    }
+
+    #[test]
+    fn loops_across_blocks() {
+        let mut wav = input(&[1.0, 2.0, 3.0], true);
+        let mut inputs = vec![vec![0.0; 4]; 1];
+
+        wav.read(&mut inputs);
+        assert_eq!(inputs[0], [1.0, 2.0, 3.0, 1.0]);
+        wav.read(&mut inputs);
+        assert_eq!(inputs[0], [2.0, 3.0, 1.0, 2.0]);
+    }
+
+    #[test]
+    fn repeats_channels_for_additional_inputs() {
+        let mut wav = input(&[1.0, 2.0], false);
+        let mut inputs = vec![vec![0.0; 2]; 2];
+
+        wav.read(&mut inputs);
+        assert_eq!(inputs, [[1.0, 2.0], [1.0, 2.0]]);
+    }
+}
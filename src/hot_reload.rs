@@ -0,0 +1,275 @@
+//! Loading generators from dynamic libraries and reloading them on changes.
+
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, SystemTime};
+
+use libloading::Library;
+
+use crate::{
+    signal, AudioMidiShell, AudioProcessor, InitContext, MidiEvent, MidiMessage, MidiSender, Param,
+    ShellError,
+};
+
+/// Name of the constructor exported by [`export_processor`](crate::export_processor).
+const CONSTRUCTOR_SYMBOL: &[u8] = b"audio_midi_shell_create_processor";
+
+/// Interval for checking the library for changes in [`AudioMidiShell::run_hot_reload`].
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Default crossfade when a reloaded generator replaces the running one.
+const RELOAD_CROSSFADE: Duration = Duration::from_millis(20);
+
+/// Counter for unique names of the library copies.
+static COPY_COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+/// Signature of the exported constructor.
+type Constructor = fn() -> Box<dyn AudioProcessor + Send>;
+
+impl AudioMidiShell {
+    /// Loads the generator from the dynamic library at `path` and runs it until SIGINT or
+    /// SIGTERM is received. Whenever the library is rebuilt, a new instance is loaded and
+    /// replaces the running one.
+    /// - `sample_rate` is the sampling frequency in Hz.
+    /// - `block_size` is the number of samples for the `process` function.
+    ///
+    /// Returns an error if the library can't be loaded initially or the shell can't be spawned.
+    /// Later loading errors are logged and the previous generator keeps running.
+    pub fn run_hot_reload(
+        sample_rate: u32,
+        block_size: usize,
+        path: impl AsRef<Path>,
+    ) -> Result<(), ShellError> {
+        let mut reloader = HotReloader::new(path);
+        let mut shell = Self::builder()
+            .sample_rate(sample_rate)
+            .block_size(block_size)
+            .spawn(reloader.load()?)?;
+
+        signal::install_handler()?;
+
+        while !signal::take_interrupt() {
+            std::thread::sleep(POLL_INTERVAL);
+
+            if let Err(error) = reloader.poll(&mut shell) {
+                log::error!("{}", error);
+            }
+        }
+
+        shell.stop::<LoadedProcessor>();
+
+        Ok(())
+    }
+}
+
+/// Watcher loading a generator or processor from a dynamic library and reloading it into a
+/// running shell when the library changes.
+///
+/// The library must be a `cdylib` exporting its constructor with
+/// [`export_processor`](crate::export_processor). It must be built with the same compiler and
+/// the same version of this crate as the application.
+///
+/// ```no_run
+/// use std::time::Duration;
+///
+/// use audio_midi_shell::{AudioMidiShell, HotReloader};
+///
+/// let mut reloader = HotReloader::new("target/debug/libsynth.so");
+/// let mut shell = AudioMidiShell::builder().spawn(reloader.load().unwrap()).unwrap();
+///
+/// loop {
+///     std::thread::sleep(Duration::from_millis(100));
+///     reloader.poll(&mut shell).ok();
+/// }
+/// ```
+#[derive(Debug)]
+pub struct HotReloader {
+    /// Location of the library.
+    path: PathBuf,
+
+    /// Crossfade between the running and the reloaded instance.
+    crossfade: Duration,
+
+    /// Modification time of the loaded library.
+    loaded: Option<SystemTime>,
+
+    /// Modification time of a changed library, reloaded once it stays unchanged for one poll.
+    pending: Option<SystemTime>,
+}
+
+impl HotReloader {
+    /// Returns a new watcher for the library at `path`.
+    pub fn new(path: impl AsRef<Path>) -> Self {
+        Self {
+            path: path.as_ref().to_owned(),
+            crossfade: RELOAD_CROSSFADE,
+            loaded: None,
+            pending: None,
+        }
+    }
+
+    /// Returns the watcher with the crossfade between the running and the reloaded instance.
+    pub fn crossfade(mut self, crossfade: Duration) -> Self {
+        self.crossfade = crossfade;
+        self
+    }
+
+    /// Loads the library and returns a new instance of its generator or processor.
+    pub fn load(&mut self) -> Result<LoadedProcessor, ShellError> {
+        let modified = self.modified()?;
+        self.loaded = Some(modified);
+        self.pending = None;
+
+        LoadedProcessor::load(&self.path)
+    }
+
+    /// Checks the library for changes and swaps a new instance into `shell` if it was rebuilt.
+    /// Returns `true` if a new instance was swapped in. Must be called periodically.
+    ///
+    /// The library is reloaded once its modification time didn't change since the previous call,
+    /// so it isn't loaded while it's still being written.
+    pub fn poll(&mut self, shell: &mut AudioMidiShell) -> Result<bool, ShellError> {
+        let modified = self.modified()?;
+
+        if self.loaded == Some(modified) {
+            self.pending = None;
+            return Ok(false);
+        }
+
+        if self.pending != Some(modified) {
+            self.pending = Some(modified);
+            return Ok(false);
+        }
+
+        let processor = self.load()?;
+        log::info!("Reloaded {}", self.path.display());
+
+        if shell.swap(processor, self.crossfade).is_err() {
+            // Retry with the next call.
+            self.loaded = None;
+            return Ok(false);
+        }
+
+        Ok(true)
+    }
+
+    /// Returns the modification time of the library.
+    fn modified(&self) -> Result<SystemTime, ShellError> {
+        std::fs::metadata(&self.path)
+            .and_then(|metadata| metadata.modified())
+            .map_err(|error| ShellError::Library(format!("{}: {}", self.path.display(), error)))
+    }
+}
+
+/// Generator or processor loaded from a dynamic library.
+pub struct LoadedProcessor {
+    /// The processor. Declared first, so it's dropped before the library is unloaded.
+    processor: Box<dyn AudioProcessor + Send>,
+
+    /// Library containing the code of the processor.
+    _library: LoadedLibrary,
+}
+
+impl LoadedProcessor {
+    /// Loads the library at `path` and creates a new instance of its processor.
+    ///
+    /// The library is copied to a unique file first. Otherwise, the operating system would
+    /// return the already loaded version when the library is rebuilt.
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, ShellError> {
+        let path = path.as_ref();
+        let error = |error: &dyn std::fmt::Display| {
+            ShellError::Library(format!("{}: {}", path.display(), error))
+        };
+
+        let file_name = path
+            .file_name()
+            .ok_or_else(|| error(&"Not a file"))?
+            .to_string_lossy();
+        let copy = std::env::temp_dir().join(format!(
+            "{}-{}-{}",
+            std::process::id(),
+            COPY_COUNTER.fetch_add(1, Ordering::Relaxed),
+            file_name
+        ));
+        std::fs::copy(path, &copy).map_err(|e| error(&e))?;
+
+        let mut library = LoadedLibrary {
+            library: None,
+            copy,
+        };
+
+        // SAFETY: The library is expected to be built with `export_processor`, using the same
+        // compiler and crate version. Its initialization code is run on loading.
+        let loaded = unsafe { Library::new(&library.copy) }.map_err(|e| error(&e))?;
+
+        // SAFETY: The symbol has the signature defined by `export_processor`.
+        let constructor = unsafe { loaded.get::<Constructor>(CONSTRUCTOR_SYMBOL) }
+            .map(|constructor| *constructor)
+            .map_err(|e| error(&e))?;
+
+        library.library = Some(loaded);
+
+        Ok(Self {
+            processor: constructor(),
+            _library: library,
+        })
+    }
+}
+
+impl AudioProcessor for LoadedProcessor {
+    fn init(&mut self, block_size: usize) {
+        self.processor.init(block_size);
+    }
+
+    fn init_with_context(&mut self, context: &InitContext) {
+        self.processor.init_with_context(context);
+    }
+
+    fn process(&mut self, inputs: &[&[f32]], outputs: &mut [&mut [f32]]) {
+        self.processor.process(inputs, outputs);
+    }
+
+    fn process_midi(&mut self, message: &[u8]) {
+        self.processor.process_midi(message);
+    }
+
+    fn process_midi_message(&mut self, message: MidiMessage) {
+        self.processor.process_midi_message(message);
+    }
+
+    fn process_midi_event(&mut self, event: MidiEvent) {
+        self.processor.process_midi_event(event);
+    }
+
+    fn init_midi_output(&mut self, sender: MidiSender) {
+        self.processor.init_midi_output(sender);
+    }
+
+    fn params(&self) -> Vec<Param> {
+        self.processor.params()
+    }
+
+    fn deinit(&mut self) {
+        self.processor.deinit();
+    }
+
+    fn min_output_channels(&self) -> usize {
+        self.processor.min_output_channels()
+    }
+}
+
+/// Loaded copy of a library, deleted when unloaded.
+struct LoadedLibrary {
+    /// The library.
+    library: Option<Library>,
+
+    /// Location of the copy.
+    copy: PathBuf,
+}
+
+impl Drop for LoadedLibrary {
+    fn drop(&mut self) {
+        drop(self.library.take());
+        std::fs::remove_file(&self.copy).ok();
+    }
+}
@@ -0,0 +1,247 @@
+//! Audio input device.
+
+use std::error::Error;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{mpsc, Arc};
+use std::thread::JoinHandle;
+
+use cpal::traits::{DeviceTrait, HostTrait, StreamTrait};
+use cpal::{FromSample, SampleFormat, SizedSample};
+use rtrb::{Consumer, Producer};
+
+use crate::WavInput;
+
+/// Number of blocks the input ring buffer can hold.
+const BUFFERED_BLOCKS: usize = 8;
+
+/// Number of blocks above which buffered input is discarded to limit the latency.
+const MAX_LATENCY_BLOCKS: usize = 4;
+
+/// Handle to the audio input device.
+///
+/// The input stream runs on its own thread and is stopped when the handle is closed or dropped.
+///
+/// The input and output devices run on independent clocks, so their sample rates drift apart
+/// slightly. The input is buffered to absorb the drift: if the input runs ahead by more than
+/// four blocks, the oldest samples are discarded, if it falls behind, the missing frames are
+/// filled with silence. Both are audible as short glitches, which occur more often the larger
+/// the drift is. Use a [`WavInput`] for glitch-free auditioning of effects.
+pub struct InputDevice {
+    /// Flag signalling the thread to stop.
+    stop: Arc<AtomicBool>,
+
+    /// Thread owning the input stream.
+    thread: Option<JoinHandle<()>>,
+}
+
+impl InputDevice {
+    /// Closes the input device. Any calls after the device was closed do nothing.
+    pub fn close(&mut self) {
+        if let Some(thread) = self.thread.take() {
+            self.stop.store(true, Ordering::Release);
+            thread.thread().unpark();
+            thread.join().ok();
+        }
+    }
+}
+
+impl Drop for InputDevice {
+    fn drop(&mut self) {
+        self.close();
+    }
+}
+
+/// Creates the ring buffer transporting interleaved samples from the input device.
+pub(crate) fn input_buffer(block_size: usize, channels: usize) -> (Producer<f32>, Consumer<f32>) {
+    rtrb::RingBuffer::new(block_size * channels * BUFFERED_BLOCKS)
+}
+
+/// Opens the default input device and writes its samples into `producer`, interleaved with
+/// the requested number of channels.
+pub(crate) fn run_input_device(
+    sample_rate: u32,
+    channels: usize,
+    producer: Producer<f32>,
+) -> Result<InputDevice, Box<dyn Error + Send + Sync>> {
+    let stop = Arc::new(AtomicBool::new(false));
+    let (result_sender, result_receiver) = mpsc::sync_channel(1);
+
+    // The stream is not `Send` on all platforms, so it is created and kept on its own thread.
+    let thread = std::thread::spawn({
+        let stop = stop.clone();
+        move || {
+            let stream = match open_stream(sample_rate, channels, producer) {
+                Ok(stream) => {
+                    result_sender.send(Ok(())).ok();
+                    stream
+                }
+                Err(error) => {
+                    result_sender.send(Err(error)).ok();
+                    return;
+                }
+            };
+
+            while !stop.load(Ordering::Acquire) {
+                std::thread::park();
+            }
+
+            drop(stream);
+        }
+    });
+
+    match result_receiver.recv() {
+        Ok(Ok(())) => Ok(InputDevice {
+            stop,
+            thread: Some(thread),
+        }),
+        Ok(Err(error)) => Err(error),
+        Err(_) => Err("Audio input thread terminated unexpectedly".into()),
+    }
+}
+
+/// Sample formats accepted from the input device, in order of preference.
+const SAMPLE_FORMATS: [SampleFormat; 7] = [
+    SampleFormat::F32,
+    SampleFormat::F64,
+    SampleFormat::I32,
+    SampleFormat::I16,
+    SampleFormat::U16,
+    SampleFormat::I8,
+    SampleFormat::U8,
+];
+
+/// Builds and starts the input stream.
+/// Device channels are repeated when the device provides fewer than the requested channels.
+///
+/// Among the device configurations supporting the sample rate, float samples and the default
+/// channel count of the device are preferred. Returns an error if the sample rate isn't supported.
+fn open_stream(
+    sample_rate: u32,
+    channels: usize,
+    producer: Producer<f32>,
+) -> Result<cpal::Stream, Box<dyn Error + Send + Sync>> {
+    let device = cpal::default_host()
+        .default_input_device()
+        .ok_or("No audio input device available")?;
+    let default_channels = device.default_input_config()?.channels();
+
+    let config = device
+        .supported_input_configs()?
+        .filter(|config| {
+            (config.min_sample_rate().0..=config.max_sample_rate().0).contains(&sample_rate)
+        })
+        .filter_map(|config| {
+            let rank = SAMPLE_FORMATS
+                .iter()
+                .position(|format| *format == config.sample_format())?;
+            Some(((rank, config.channels() != default_channels), config))
+        })
+        .min_by_key(|(rank, _)| *rank)
+        .map(|(_, config)| config.with_sample_rate(cpal::SampleRate(sample_rate)))
+        .ok_or_else(|| format!("Sample rate of {} Hz not supported", sample_rate))?;
+
+    if let Ok(name) = device.name() {
+        log::info!(
+            "Opening audio input {} with {} channels of {:?} samples",
+            name,
+            config.channels(),
+            config.sample_format()
+        );
+    }
+
+    let sample_format = config.sample_format();
+    let config = config.config();
+    let stream = match sample_format {
+        SampleFormat::F32 => build_stream::<f32>(&device, &config, channels, producer),
+        SampleFormat::F64 => build_stream::<f64>(&device, &config, channels, producer),
+        SampleFormat::I32 => build_stream::<i32>(&device, &config, channels, producer),
+        SampleFormat::I16 => build_stream::<i16>(&device, &config, channels, producer),
+        SampleFormat::U16 => build_stream::<u16>(&device, &config, channels, producer),
+        SampleFormat::I8 => build_stream::<i8>(&device, &config, channels, producer),
+        SampleFormat::U8 => build_stream::<u8>(&device, &config, channels, producer),
+        format => return Err(format!("Unsupported sample format {:?}", format).into()),
+    }?;
+    stream.play()?;
+
+    Ok(stream)
+}
+
+/// Builds the input stream for samples of type `T`, converting them to `f32`.
+fn build_stream<T>(
+    device: &cpal::Device,
+    config: &cpal::StreamConfig,
+    channels: usize,
+    mut producer: Producer<f32>,
+) -> Result<cpal::Stream, cpal::BuildStreamError>
+where
+    T: SizedSample,
+    f32: FromSample<T>,
+{
+    let device_channels = config.channels as usize;
+
+    device.build_input_stream(
+        config,
+        move |data: &[T], _info| {
+            for frame in data.chunks(device_channels) {
+                if producer.slots() >= channels {
+                    for channel_no in 0..channels {
+                        producer
+                            .push(frame[channel_no % frame.len()].to_sample::<f32>())
+                            .ok();
+                    }
+                }
+            }
+        },
+        |error| log::error!("Audio input error: {}", error),
+        None,
+    )
+}
+
+/// Source of the input signal of the audio callback.
+pub(crate) enum InputSource {
+    /// Interleaved samples of the input device.
+    Device(Consumer<f32>),
+
+    /// Samples of a file.
+    File(WavInput),
+}
+
+impl InputSource {
+    /// Reads a block of samples into the input buffers.
+    pub fn read(&mut self, inputs: &mut [Vec<f32>]) {
+        match self {
+            Self::Device(consumer) => read_input(consumer, inputs),
+            Self::File(file) => file.read(inputs),
+        }
+    }
+}
+
+/// Reads a block of interleaved samples from `consumer` into the input buffers.
+/// Missing samples are filled with silence.
+fn read_input(consumer: &mut Consumer<f32>, inputs: &mut [Vec<f32>]) {
+    let channels = inputs.len();
+    let block_size = inputs[0].len();
+
+    // Drop the oldest samples when the input device runs ahead of the output device.
+    let max_latency = block_size * channels * MAX_LATENCY_BLOCKS;
+    let available = consumer.slots();
+    if available > max_latency {
+        let excess = available - block_size * channels;
+        let excess = excess - excess % channels;
+        if let Ok(chunk) = consumer.read_chunk(excess) {
+            chunk.commit_all();
+        }
+    }
+
+    for frame_no in 0..block_size {
+        let complete = consumer.slots() >= channels;
+
+        for input in inputs.iter_mut() {
+            input[frame_no] = if complete {
+                consumer.pop().unwrap_or(0.0)
+            } else {
+                0.0
+            };
+        }
+    }
+}
@@ -1,21 +1,108 @@
 #![doc = include_str!("../README.md")]
 #![warn(missing_docs)]
 
+mod builder;
+mod command;
+mod context;
+mod engine;
+mod error;
+mod fade;
+mod file_input;
+#[cfg(feature = "hot-reload")]
+mod hot_reload;
+mod input;
+mod midi;
+mod midi_input;
+mod midi_message;
+mod offline;
+mod output;
+mod param;
+mod port_filter;
+mod protection;
+mod recording;
+mod signal;
+mod stats;
+mod swap;
+
+use std::any::Any;
+use std::path::Path;
 use std::sync::mpsc;
+use std::time::Duration;
+
+use tinyaudio::OutputDevice;
 
-use midir::{MidiInput, MidiInputConnection};
-use tinyaudio::{run_output_device, OutputDevice, OutputDeviceParameters};
+pub use builder::AudioMidiShellBuilder;
+use command::WithCommands;
+pub use command::{CommandHandler, CommandSender};
+pub use context::InitContext;
+pub use engine::MAX_CHANNELS;
+pub use error::ShellError;
+use fade::FadeOutTrigger;
+pub use file_input::WavInput;
+#[cfg(feature = "hot-reload")]
+pub use hot_reload::{HotReloader, LoadedProcessor};
+pub use input::InputDevice;
+use midi::MidiOutputForwarder;
+pub use midi::{MidiEvent, MidiSender, MAX_MESSAGE_SIZE};
+use midi_input::MidiInputs;
+pub use midi_input::MidiPortEvent;
+pub use midi_message::MidiMessage;
+pub use offline::{CapturedOutput, TestShell};
+pub use param::Param;
+pub use port_filter::{MidiPortFilter, PortMatcher};
+use protection::ProtectionEvents;
+pub use protection::{OutputProtection, ProtectionEvent};
+use recording::Recorder;
+pub use recording::RecordingFormat;
+use stats::StatsReader;
+pub use stats::{CallbackStats, LOAD_HISTOGRAM_BINS};
+use swap::{IntoAny, SwapSender};
 
 /// Shell running the audio and MIDI processing.
 pub struct AudioMidiShell {
-    /// MIDI connections.
-    pub midi_connections: MidiConnections,
-
     /// Output device:
     pub output_device: OutputDevice,
+
+    /// Input device, only opened for processors.
+    pub input_device: Option<InputDevice>,
+
+    /// Forwarder for the MIDI messages sent by the processor.
+    midi_output: MidiOutputForwarder,
+
+    /// MIDI input connections, `None` if MIDI is disabled.
+    midi_inputs: Option<MidiInputs>,
+
+    /// Parameters declared by the processor.
+    params: Vec<Param>,
+
+    /// Producer for the commands, `None` if the shell was spawned without commands.
+    commands: Option<Box<dyn Any + Send + Sync>>,
+
+    /// Receiver for the processor, handed back when the audio callback is dropped.
+    processor: mpsc::Receiver<Box<dyn Any + Send>>,
+
+    /// Trigger for the fade-out before stopping.
+    fade_out: FadeOutTrigger,
+
+    /// Sender for replacing the processor.
+    swap: SwapSender,
+
+    /// Load statistics of the audio callback.
+    stats: StatsReader,
+
+    /// Receiver for the events of the output protection, `None` if disabled.
+    protection_events: Option<ProtectionEvents>,
+
+    /// Recorder of the output.
+    recorder: Recorder,
 }
 
 impl AudioMidiShell {
+    /// Returns a builder for a shell with custom configuration.
+    pub fn builder() -> AudioMidiShellBuilder {
+        AudioMidiShellBuilder::new()
+    }
+
     /// Initializes the MIDI inputs, the output device and runs the generator in a callback.
     /// It returns a shell object that must be kept alive.
     /// - `sample_rate` is the sampling frequency in Hz.
@@ -23,40 +110,44 @@ impl AudioMidiShell {
     pub fn spawn(
         sample_rate: u32,
         block_size: usize,
-        mut generator: impl AudioGenerator + Send + 'static,
+        generator: impl AudioGenerator + Send + 'static,
     ) -> Self {
-        let (midi_sender, midi_receiver): (mpsc::Sender<Vec<u8>>, mpsc::Receiver<Vec<u8>>) =
-            mpsc::channel();
-        let midi_connections = init_midi(midi_sender);
-
-        generator.init(block_size);
-
-        let params = OutputDeviceParameters {
-            channels_count: 2,
-            sample_rate: sample_rate as usize,
-            channel_sample_count: block_size,
-        };
-
-        let output_device = run_output_device(params, move |data| {
-            while let Ok(message) = midi_receiver.try_recv() {
-                generator.process_midi(message);
-            }
-
-            let mut samples_left = vec![0.0; block_size];
-            let mut samples_right = vec![0.0; block_size];
-            generator.process(&mut samples_left, &mut samples_right);
-
-            for (frame_no, samples) in data.chunks_mut(params.channels_count).enumerate() {
-                samples[0] = samples_left[frame_no];
-                samples[1] = samples_right[frame_no];
-            }
-        })
-        .unwrap();
-
-        Self {
-            midi_connections,
-            output_device,
-        }
+        Self::try_spawn(sample_rate, block_size, generator).unwrap()
+    }
+
+    /// Initializes the MIDI inputs, the output device and runs the generator in a callback.
+    /// Returns an error if the output device can't be opened.
+    /// - `sample_rate` is the sampling frequency in Hz.
+    /// - `block_size` is the number of samples for the `process` function.
+    ///
+    /// MIDI errors are not fatal. If MIDI is unavailable, the shell runs audio only.
+    /// The errors are reported via [`AudioMidiShell::midi_port_events`].
+    pub fn try_spawn(
+        sample_rate: u32,
+        block_size: usize,
+        generator: impl AudioGenerator + Send + 'static,
+    ) -> Result<Self, ShellError> {
+        Self::builder()
+            .sample_rate(sample_rate)
+            .block_size(block_size)
+            .spawn(generator)
+    }
+
+    /// Initializes the MIDI inputs, the input and output devices and runs the processor in a
+    /// callback. It returns a shell object that must be kept alive.
+    /// - `sample_rate` is the sampling frequency in Hz.
+    /// - `block_size` is the number of samples for the `process` function.
+    pub fn spawn_processor(
+        sample_rate: u32,
+        block_size: usize,
+        processor: impl AudioProcessor + Send + 'static,
+    ) -> Self {
+        Self::builder()
+            .sample_rate(sample_rate)
+            .block_size(block_size)
+            .input_channels(2)
+            .spawn(processor)
+            .unwrap()
     }
 
     /// Spawns the shell and keeps it alive forever.
@@ -70,52 +161,432 @@ impl AudioMidiShell {
         let _shell = Self::spawn(sample_rate, block_size, generator);
 
         loop {
-            std::thread::sleep(std::time::Duration::from_millis(100));
+            std::thread::sleep(Duration::from_millis(100));
+        }
+    }
+
+    /// Spawns the shell with a processor and keeps it alive forever.
+    /// - `sample_rate` is the sampling frequency in Hz.
+    /// - `block_size` is the number of samples for the `process` function.
+    pub fn run_processor_forever(
+        sample_rate: u32,
+        block_size: usize,
+        processor: impl AudioProcessor + Send + 'static,
+    ) -> ! {
+        let _shell = Self::spawn_processor(sample_rate, block_size, processor);
+
+        loop {
+            std::thread::sleep(Duration::from_millis(100));
+        }
+    }
+
+    /// Spawns the shell and keeps it alive until SIGINT or SIGTERM is received, e.g. by
+    /// pressing Ctrl-C. The output is faded out and the shell is stopped before the generator is
+    /// returned.
+    /// - `sample_rate` is the sampling frequency in Hz.
+    /// - `block_size` is the number of samples for the `process` function.
+    pub fn run_until_interrupted<G: AudioGenerator + Send + 'static>(
+        sample_rate: u32,
+        block_size: usize,
+        generator: G,
+    ) -> Result<G, ShellError> {
+        let shell = Self::try_spawn(sample_rate, block_size, generator)?;
+        Self::wait_for_interrupt()?;
+
+        shell.stop().ok_or(ShellError::ProcessorNotReturned)
+    }
+
+    /// Blocks until SIGINT or SIGTERM is received, e.g. by pressing Ctrl-C.
+    /// Call [`AudioMidiShell::stop`] afterwards for an orderly shutdown.
+    /// Returns an error if the signal handler can't be installed.
+    pub fn wait_for_interrupt() -> Result<(), ShellError> {
+        signal::wait_for_interrupt()
+    }
+
+    /// Shuts down the shell and returns the generator or processor.
+    /// The MIDI inputs are closed first, then the output is faded out, the output device is
+    /// stopped and `deinit` is called. Finally the MIDI outputs and the input device are closed.
+    ///
+    /// `G` is the type passed to the spawn function. Returns `None` if it doesn't match or
+    /// the processor was not handed back by the audio callback within one second.
+    pub fn stop<G: 'static>(self) -> Option<G> {
+        let Self {
+            mut output_device,
+            input_device,
+            midi_output,
+            midi_inputs,
+            processor,
+            fade_out,
+            mut swap,
+            mut recorder,
+            ..
+        } = self;
+
+        drop(midi_inputs);
+        fade_out.fade_out();
+        output_device.close();
+        if let Err(error) = recorder.stop() {
+            log::error!("{}", error);
+        }
+        swap.dispose_retired();
+        drop(midi_output);
+        drop(input_device);
+
+        processor
+            .recv_timeout(Duration::from_secs(1))
+            .ok()?
+            .downcast()
+            .ok()
+            .map(|processor| *processor)
+    }
+
+    /// Replaces the running generator or processor without reopening the audio devices and
+    /// MIDI ports. The outputs of both are crossfaded linearly over `crossfade`.
+    /// Afterwards, [`AudioMidiShell::stop`] returns the new one and [`AudioMidiShell::params`]
+    /// the parameters it declares.
+    ///
+    /// Afterwards, [`AudioMidiShell::send`] returns the commands instead of delivering them, as
+    /// does the [`CommandSender`] of the previous processor once it's released. Use
+    /// [`AudioMidiShell::swap_with_commands`] for processors receiving commands.
+    /// Returns the processor if too many swaps are pending.
+    pub fn swap<P>(&mut self, processor: P, crossfade: Duration) -> Result<(), P>
+    where
+        P: AudioProcessor + Send + 'static,
+    {
+        self.swap_returning(processor, |processor| Box::new(processor), crossfade)?;
+        self.commands = None;
+
+        Ok(())
+    }
+
+    /// Like [`AudioMidiShell::swap`], but additionally opens a queue for the commands of the new
+    /// processor, replacing the previous one, and returns the handle for sending them.
+    pub fn swap_with_commands<P>(
+        &mut self,
+        processor: P,
+        crossfade: Duration,
+    ) -> Result<CommandSender<P::Command>, P>
+    where
+        P: AudioProcessor + CommandHandler + Send + 'static,
+    {
+        if self.swap.is_full() {
+            return Err(processor);
+        }
+
+        let (sender, consumer) =
+            command::command_queue::<P::Command>(self.swap.config().command_queue_size);
+        self.swap_returning(
+            WithCommands::new(processor, consumer),
+            |processor| Box::new(processor.into_inner()),
+            crossfade,
+        )
+        .map_err(WithCommands::into_inner)?;
+        self.commands = Some(Box::new(sender.clone()));
+
+        Ok(sender)
+    }
+
+    /// Replaces the processor, which is handed back converted by `into_any` when stopped.
+    fn swap_returning<P>(
+        &mut self,
+        processor: P,
+        into_any: IntoAny<P>,
+        crossfade: Duration,
+    ) -> Result<(), P>
+    where
+        P: AudioProcessor + Send + 'static,
+    {
+        if self.swap.is_full() {
+            return Err(processor);
+        }
+
+        let (midi_sender, midi_output_consumer) =
+            midi::midi_output_queue(self.swap.config().midi_queue_size);
+        self.midi_output.add_queue(midi_output_consumer);
+
+        self.params = self
+            .swap
+            .send(swap::boxed(processor, into_any), midi_sender, crossfade);
+
+        Ok(())
+    }
+
+    /// Returns the load statistics of the audio callback since the start or the last reset.
+    pub fn stats(&self) -> CallbackStats {
+        self.stats.stats()
+    }
+
+    /// Resets the load statistics, e.g. to measure only a certain passage.
+    pub fn reset_stats(&self) {
+        self.stats.reset();
+    }
+
+    /// Returns the problems detected by the output protection since the last call.
+    /// Always empty if the protection wasn't enabled with
+    /// [`AudioMidiShellBuilder::output_protection`].
+    pub fn protection_events(&self) -> Vec<ProtectionEvent> {
+        self.protection_events
+            .as_ref()
+            .map(ProtectionEvents::take)
+            .unwrap_or_default()
+    }
+
+    /// Starts recording the output into a stereo WAV file at `path`, exactly as written to the
+    /// audio device. A running recording is stopped before. For more than two output channels,
+    /// the first two are recorded, mono output is recorded on both channels.
+    ///
+    /// The samples are passed to a writer thread without blocking the audio callback. Returns an
+    /// error if the file can't be created, or if the previous recording failed.
+    pub fn start_recording(
+        &mut self,
+        path: impl AsRef<Path>,
+        format: RecordingFormat,
+    ) -> Result<(), ShellError> {
+        self.recorder.start(path.as_ref(), format)
+    }
+
+    /// Stops the running recording and waits until the file is finalized. Returns the error if
+    /// writing the file failed, e.g. because the disk is full. Does nothing if no recording is
+    /// running. The recording is also stopped by [`AudioMidiShell::stop`] after the
+    /// fade-out, or when the shell is dropped.
+    pub fn stop_recording(&mut self) -> Result<(), ShellError> {
+        self.recorder.stop()
+    }
+
+    /// Returns if a recording is running. Turns `false` once writing the file failed, the error
+    /// is then returned by [`AudioMidiShell::stop_recording`].
+    pub fn is_recording(&self) -> bool {
+        self.recorder.is_recording()
+    }
+
+    /// Returns the names of the connected MIDI input ports.
+    pub fn connected_midi_inputs(&self) -> Vec<String> {
+        self.midi_inputs
+            .as_ref()
+            .map(MidiInputs::port_names)
+            .unwrap_or_default()
+    }
+
+    /// Returns the changes of the connected MIDI input ports and MIDI errors since the last call.
+    /// The ports are rescanned periodically, so devices can be plugged in and out while the
+    /// shell is running.
+    pub fn midi_port_events(&self) -> impl Iterator<Item = MidiPortEvent> + '_ {
+        self.midi_inputs.iter().flat_map(MidiInputs::events)
+    }
+
+    /// Returns the names of all available MIDI input ports.
+    /// The position in the list is the index used by [`PortMatcher::Index`], as long as no
+    /// devices are plugged in or out before the shell is started.
+    pub fn midi_input_ports() -> Vec<String> {
+        midi_input::midi_input_port_names()
+    }
+
+    /// Connects the MIDI messages sent by the processor to the first output port whose name
+    /// contains `port_name`. Returns the full name of the connected port.
+    /// Can be called multiple times to forward the messages to several ports.
+    pub fn connect_midi_output(&self, port_name: &str) -> Result<String, ShellError> {
+        self.midi_output.connect(port_name)
+    }
+
+    /// Returns the names of all available MIDI output ports.
+    pub fn midi_output_ports() -> Vec<String> {
+        midi::midi_output_port_names()
+    }
+
+    /// Returns the parameters declared by the processor.
+    pub fn params(&self) -> &[Param] {
+        &self.params
+    }
+
+    /// Returns the parameter named `name`.
+    pub fn param(&self, name: &str) -> Option<&Param> {
+        self.params.iter().find(|param| param.name() == name)
+    }
+
+    /// Sends a command to the processor, which receives it via
+    /// [`CommandHandler::process_command`] before the next block is processed.
+    /// Returns the command if the queue is full, or if the shell was not spawned with
+    /// [`AudioMidiShellBuilder::spawn_with_commands`] for commands of this type.
+    ///
+    /// Prefer the [`CommandSender`] returned when spawning, which checks the command type at
+    /// compile time.
+    pub fn send<C: Send + 'static>(&self, command: C) -> Result<(), C> {
+        match self
+            .commands
+            .as_ref()
+            .and_then(|sender| sender.downcast_ref::<CommandSender<C>>())
+        {
+            Some(sender) => sender.send(command),
+            None => Err(command),
         }
     }
 }
 
+/// Exports the constructor of a generator or processor from a dynamic library, so it can be
+/// loaded with the `HotReloader` of the `hot-reload` feature.
+///
+/// The library must be built as `cdylib` with the same compiler and the same version of this
+/// crate as the application loading it.
+///
+/// ```ignore
+/// audio_midi_shell::export_processor!(SineSynth::default());
+/// ```
+#[macro_export]
+macro_rules! export_processor {
+    ($constructor:expr) => {
+        #[no_mangle]
+        pub fn audio_midi_shell_create_processor(
+        ) -> ::std::boxed::Box<dyn $crate::AudioProcessor + ::std::marker::Send> {
+            ::std::boxed::Box::new($constructor)
+        }
+    };
+}
+
 /// Trait to be implemented by structs that are passed as generator to the shell.
 pub trait AudioGenerator {
     /// Initializes the generator. Called once inside the shell `run` function.
     fn init(&mut self, _block_size: usize) {}
 
+    /// Initializes the generator with the settings of the shell. Called once instead of `init`.
+    /// The default implementation calls `init` with the maximum block size.
+    fn init_with_context(&mut self, context: &InitContext) {
+        self.init(context.max_block_size);
+    }
+
     /// Generates a block of samples.
     /// `samples_left` and `samples_right` are buffers of the block size passed to the shell `run`
-    /// function. They are initialized to `0.0` and must be filled with sample data.
+    /// function, or shorter if the blocks are split at MIDI events. They are initialized to `0.0`
+    /// and must be filled with sample data.
     fn process(&mut self, samples_left: &mut [f32], samples_right: &mut [f32]);
 
     /// Processes a MIDI message.
-    fn process_midi(&mut self, _message: Vec<u8>) {}
+    fn process_midi(&mut self, _message: &[u8]) {}
+
+    /// Processes a decoded MIDI message.
+    fn process_midi_message(&mut self, _message: MidiMessage) {}
+
+    /// Receives the handle for sending MIDI messages. Called once after `init_with_context`.
+    fn init_midi_output(&mut self, _sender: MidiSender) {}
+
+    /// Deinitializes the generator. Called once after the last block when the shell is stopped
+    /// or dropped.
+    fn deinit(&mut self) {}
+
+    /// Returns the parameters that can be set from the application or via MIDI controllers.
+    /// Called once after `init_midi_output`. The returned handles share their values with the
+    /// ones kept by the implementation.
+    fn params(&self) -> Vec<Param> {
+        Vec::new()
+    }
+
+    /// Processes a MIDI event carrying the frame offset into the next processed block.
+    /// The default implementation discards the offset, calls `process_midi_message` if the
+    /// message can be decoded and then `process_midi` with the raw bytes.
+    fn process_midi_event(&mut self, event: MidiEvent) {
+        if let Some(message) = event.parse() {
+            self.process_midi_message(message);
+        }
+        self.process_midi(event.data());
+    }
 }
 
-/// Vector of MIDI connections with an attached mpsc sender.
-type MidiConnections = Vec<MidiInputConnection<mpsc::Sender<Vec<u8>>>>;
-
-/// Connects all available MIDI inputs to an mpsc sender and returns them in a vector.
-fn init_midi(sender: mpsc::Sender<Vec<u8>>) -> MidiConnections {
-    let mut connections = MidiConnections::new();
-
-    let input = MidiInput::new(&(env!("CARGO_PKG_NAME").to_owned() + " scan input"))
-        .expect("MIDI Input error");
-
-    for port in input.ports().iter() {
-        let input = MidiInput::new(&(env!("CARGO_PKG_NAME").to_owned() + " input"))
-            .expect("MIDI Input error");
-        let port_name = input.port_name(port).unwrap();
-        log::info!("Connecting to MIDI input {}", port_name);
-        let conn = input
-            .connect(
-                port,
-                port_name.as_str(),
-                |_timestamp, message, sender| {
-                    sender.send(Vec::from(message)).ok();
-                },
-                sender.clone(),
-            )
-            .ok();
-        connections.push(conn.unwrap());
-    }
-
-    connections
+/// Trait to be implemented by structs that process an input signal, e.g. effects.
+///
+/// All generators implement this trait as well. They ignore the inputs and render into the first
+/// two outputs, the remaining outputs stay silent. On a mono output, both channels are mixed down.
+pub trait AudioProcessor {
+    /// Initializes the processor. Called once inside the shell `run` function.
+    fn init(&mut self, _block_size: usize) {}
+
+    /// Initializes the processor with the settings of the shell. Called once instead of `init`.
+    /// The default implementation calls `init` with the maximum block size.
+    fn init_with_context(&mut self, context: &InitContext) {
+        self.init(context.max_block_size);
+    }
+
+    /// Processes a block of samples.
+    /// `inputs` and `outputs` contain one buffer per channel, each of the block size passed to the
+    /// shell `run` function, or shorter if the blocks are split at MIDI events. The outputs are
+    /// initialized to `0.0` and must be filled with sample data.
+    fn process(&mut self, inputs: &[&[f32]], outputs: &mut [&mut [f32]]);
+
+    /// Processes a MIDI message.
+    fn process_midi(&mut self, _message: &[u8]) {}
+
+    /// Processes a decoded MIDI message.
+    fn process_midi_message(&mut self, _message: MidiMessage) {}
+
+    /// Receives the handle for sending MIDI messages. Called once after `init_with_context`.
+    fn init_midi_output(&mut self, _sender: MidiSender) {}
+
+    /// Deinitializes the generator. Called once after the last block when the shell is stopped
+    /// or dropped.
+    fn deinit(&mut self) {}
+
+    /// Returns the parameters that can be set from the application or via MIDI controllers.
+    /// Called once after `init_midi_output`. The returned handles share their values with the
+    /// ones kept by the implementation.
+    fn params(&self) -> Vec<Param> {
+        Vec::new()
+    }
+
+    /// Processes a MIDI event carrying the frame offset into the next processed block.
+    /// The default implementation discards the offset, calls `process_midi_message` if the
+    /// message can be decoded and then `process_midi` with the raw bytes.
+    fn process_midi_event(&mut self, event: MidiEvent) {
+        if let Some(message) = event.parse() {
+            self.process_midi_message(message);
+        }
+        self.process_midi(event.data());
+    }
+
+    /// Returns the minimum number of output buffers the processor renders into.
+    /// If the shell runs with fewer output channels, the additional buffers are mixed down.
+    fn min_output_channels(&self) -> usize {
+        1
+    }
+}
+
+impl<G: AudioGenerator> AudioProcessor for G {
+    fn init(&mut self, block_size: usize) {
+        AudioGenerator::init(self, block_size);
+    }
+
+    fn init_with_context(&mut self, context: &InitContext) {
+        AudioGenerator::init_with_context(self, context);
+    }
+
+    fn process(&mut self, _inputs: &[&[f32]], outputs: &mut [&mut [f32]]) {
+        if let [samples_left, samples_right, ..] = outputs {
+            AudioGenerator::process(self, samples_left, samples_right);
+        }
+    }
+
+    fn process_midi(&mut self, message: &[u8]) {
+        AudioGenerator::process_midi(self, message);
+    }
+
+    fn process_midi_message(&mut self, message: MidiMessage) {
+        AudioGenerator::process_midi_message(self, message);
+    }
+
+    fn process_midi_event(&mut self, event: MidiEvent) {
+        AudioGenerator::process_midi_event(self, event);
+    }
+
+    fn init_midi_output(&mut self, sender: MidiSender) {
+        AudioGenerator::init_midi_output(self, sender);
+    }
+
+    fn params(&self) -> Vec<Param> {
+        AudioGenerator::params(self)
+    }
+
+    fn deinit(&mut self) {
+        AudioGenerator::deinit(self);
+    }
+
+    fn min_output_channels(&self) -> usize {
+        2
+    }
 }
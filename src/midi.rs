@@ -0,0 +1,383 @@
+//! MIDI events, queues and timing.
+
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+use midir::{MidiOutput, MidiOutputConnection};
+use rtrb::{Consumer, Producer, RingBuffer};
+
+use crate::{MidiMessage, ShellError};
+
+/// Maximum size of a MIDI message in bytes. Longer system exclusive messages are dropped.
+pub const MAX_MESSAGE_SIZE: usize = 256;
+
+/// Default number of MIDI events that can be queued in each direction.
+pub(crate) const MIDI_QUEUE_SIZE: usize = 1024;
+
+/// MIDI message together with its position in the current block.
+///
+/// The message bytes are stored inline, so events can be passed around without allocation.
+#[derive(Clone, Copy)]
+pub struct MidiEvent {
+    /// Frame offset into the current block.
+    frame: usize,
+
+    /// Number of valid bytes in `data`.
+    len: usize,
+
+    /// Raw message bytes.
+    data: [u8; MAX_MESSAGE_SIZE],
+}
+
+impl MidiEvent {
+    /// Returns a new event or `None` if the message exceeds [`MAX_MESSAGE_SIZE`].
+    /// - `frame` is the offset into the block at which the event takes effect.
+    /// - `message` contains the raw message bytes.
+    pub fn new(frame: usize, message: &[u8]) -> Option<Self> {
+        let mut data = [0; MAX_MESSAGE_SIZE];
+        data.get_mut(..message.len())?.copy_from_slice(message);
+
+        Some(Self {
+            frame,
+            len: message.len(),
+            data,
+        })
+    }
+
+    /// Returns the frame offset into the current block.
+    pub fn frame(&self) -> usize {
+        self.frame
+    }
+
+    /// Returns a copy of the event with another frame offset.
+    pub fn with_frame(self, frame: usize) -> Self {
+        Self { frame, ..self }
+    }
+
+    /// Returns the raw message bytes.
+    pub fn data(&self) -> &[u8] {
+        &self.data[..self.len]
+    }
+
+    /// Decodes the message. Returns `None` if the message is invalid or unknown.
+    pub fn parse(&self) -> Option<MidiMessage<'_>> {
+        MidiMessage::parse(self.data())
+    }
+}
+
+impl PartialEq for MidiEvent {
+    fn eq(&self, other: &Self) -> bool {
+        self.frame == other.frame && self.data() == other.data()
+    }
+}
+
+impl Eq for MidiEvent {}
+
+impl std::fmt::Debug for MidiEvent {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("MidiEvent")
+            .field("frame", &self.frame)
+            .field("data", &self.data())
+            .finish()
+    }
+}
+
+/// MIDI event received from an input port, timestamped in microseconds
+/// relative to the start of the shell.
+pub(crate) type TimedEvent = (u64, MidiEvent);
+
+/// Producer side of the MIDI queue, shared by all input connections.
+pub(crate) type MidiProducer = Arc<Mutex<Producer<TimedEvent>>>;
+
+/// Creates the queue transporting MIDI events from the input connections to the audio callback.
+/// - `size` is the number of events that can be queued.
+pub(crate) fn midi_queue(size: usize) -> (MidiProducer, Consumer<TimedEvent>) {
+    let (producer, consumer) = RingBuffer::new(size);
+    (Arc::new(Mutex::new(producer)), consumer)
+}
+
+/// Maps message timestamps to frame offsets within the blocks of the audio callback.
+///
+/// Messages received during one callback period are spread over the blocks written by the
+/// following callback, which adds a constant latency of one callback period, or one block if
+/// longer, but removes the jitter. The clock is synchronized to the wall-clock time once per
+/// callback, the blocks within a callback advance by their nominal duration.
+pub(crate) struct BlockClock {
+    /// Reference point of the shell clock.
+    start: Instant,
+
+    /// Sampling frequency in Hz.
+    sample_rate: u32,
+
+    /// Number of frames per block.
+    block_size: usize,
+
+    /// Time corresponding to the first frame of the current callback in microseconds.
+    callback_start: u64,
+
+    /// Start time of the current block in microseconds.
+    block_start: u64,
+
+    /// Frame offset of the last event, used to keep the offsets in order.
+    last_frame: usize,
+}
+
+impl BlockClock {
+    /// Returns a new clock.
+    pub fn new(start: Instant, sample_rate: u32, block_size: usize) -> Self {
+        Self {
+            start,
+            sample_rate,
+            block_size,
+            callback_start: 0,
+            block_start: 0,
+            last_frame: 0,
+        }
+    }
+
+    /// Synchronizes the clock to the wall-clock time. Must be called at the beginning of each
+    /// callback.
+    /// - `frame_count` is the number of frames written by the callback.
+    pub fn begin_callback(&mut self, frame_count: usize) {
+        let now = self.start.elapsed().as_micros() as u64;
+        let latency = self.duration(frame_count.max(self.block_size));
+
+        self.callback_start = now.saturating_sub(latency);
+    }
+
+    /// Marks the start of a new block before converting timestamps.
+    /// - `frame_offset` is the position of the block's first frame within the current callback.
+    pub fn begin_block(&mut self, frame_offset: usize) {
+        self.block_start = self.callback_start + self.duration(frame_offset);
+        self.last_frame = 0;
+    }
+
+    /// Returns if a message with the timestamp `time` belongs to the current block or an
+    /// earlier one. Later messages are left for the following blocks.
+    pub fn is_due(&self, time: u64) -> bool {
+        time < self.block_start + self.duration(self.block_size)
+    }
+
+    /// Returns the duration of `frame_count` frames in microseconds.
+    fn duration(&self, frame_count: usize) -> u64 {
+        frame_count as u64 * 1_000_000 / self.sample_rate as u64
+    }
+
+    /// Returns the frame offset into the current block for a message timestamp.
+    pub fn frame(&mut self, time: u64) -> usize {
+        let elapsed = time.saturating_sub(self.block_start);
+        let frame = (elapsed * self.sample_rate as u64 / 1_000_000) as usize;
+        let frame = frame.min(self.block_size - 1).max(self.last_frame);
+        self.last_frame = frame;
+
+        frame
+    }
+}
+
+/// Handle for sending MIDI messages from the processor to the output ports.
+///
+/// Sending does not allocate or lock, so it can be used inside the `process` functions.
+pub struct MidiSender {
+    /// Producer for the outgoing events.
+    producer: Producer<MidiEvent>,
+
+    /// Start of the processed part of the block, added to the frame offsets when blocks are
+    /// split at MIDI events.
+    frame_offset: Arc<AtomicUsize>,
+}
+
+impl MidiSender {
+    /// Queues a message for output.
+    /// Returns `false` if the message exceeds [`MAX_MESSAGE_SIZE`] or the queue is full.
+    /// - `frame` is the offset into the current buffers. If blocks are split at MIDI events,
+    ///   it's relative to the part passed to `process`, or to the position of the received event.
+    ///
+    /// Messages are forwarded to the connected output ports as soon as possible after the
+    /// block was processed, the frame offset is only evaluated by the [`TestShell`](crate::TestShell).
+    pub fn send(&mut self, frame: usize, message: &[u8]) -> bool {
+        let frame = frame + self.frame_offset.load(Ordering::Relaxed);
+        MidiEvent::new(frame, message).is_some_and(|event| self.producer.push(event).is_ok())
+    }
+
+    /// Returns the handle for setting the start of the processed part of the block.
+    pub(crate) fn frame_offset(&self) -> Arc<AtomicUsize> {
+        self.frame_offset.clone()
+    }
+}
+
+/// Creates the queue transporting MIDI events from the processor to the output ports.
+/// - `size` is the number of events that can be queued.
+pub(crate) fn midi_output_queue(size: usize) -> (MidiSender, Consumer<MidiEvent>) {
+    let (producer, consumer) = RingBuffer::new(size);
+    let sender = MidiSender {
+        producer,
+        frame_offset: Arc::new(AtomicUsize::new(0)),
+    };
+
+    (sender, consumer)
+}
+
+/// Interval for polling the outgoing MIDI queue.
+const MIDI_OUTPUT_POLL_INTERVAL: Duration = Duration::from_millis(1);
+
+/// Thread forwarding the messages sent by the processors to the connected output ports.
+pub(crate) struct MidiOutputForwarder {
+    /// Connected output ports.
+    connections: Arc<Mutex<Vec<MidiOutputConnection>>>,
+
+    /// Consumers for the messages of the processors.
+    consumers: Arc<Mutex<Vec<Consumer<MidiEvent>>>>,
+
+    /// Flag signalling the thread to stop.
+    stop: Arc<AtomicBool>,
+
+    /// Forwarding thread.
+    thread: Option<JoinHandle<()>>,
+}
+
+impl MidiOutputForwarder {
+    /// Starts the forwarding thread reading from `consumer`.
+    pub fn new(consumer: Consumer<MidiEvent>) -> Self {
+        let connections = Arc::new(Mutex::new(Vec::<MidiOutputConnection>::new()));
+        let consumers = Arc::new(Mutex::new(vec![consumer]));
+        let stop = Arc::new(AtomicBool::new(false));
+
+        let thread = std::thread::spawn({
+            let connections = connections.clone();
+            let consumers = consumers.clone();
+            let stop = stop.clone();
+            move || {
+                while !stop.load(Ordering::Acquire) {
+                    if let (Ok(mut consumers), Ok(mut connections)) =
+                        (consumers.lock(), connections.lock())
+                    {
+                        for consumer in consumers.iter_mut() {
+                            while let Ok(event) = consumer.pop() {
+                                for connection in connections.iter_mut() {
+                                    if let Err(error) = connection.send(event.data()) {
+                                        log::warn!("MIDI output error: {}", error);
+                                    }
+                                }
+                            }
+                        }
+
+                        // Queues of replaced processors are dropped once drained.
+                        consumers.retain(|consumer| !consumer.is_abandoned());
+                    }
+
+                    std::thread::sleep(MIDI_OUTPUT_POLL_INTERVAL);
+                }
+            }
+        });
+
+        Self {
+            connections,
+            consumers,
+            stop,
+            thread: Some(thread),
+        }
+    }
+
+    /// Forwards the messages from `consumer` in addition to the existing queues.
+    pub fn add_queue(&self, consumer: Consumer<MidiEvent>) {
+        if let Ok(mut consumers) = self.consumers.lock() {
+            consumers.push(consumer);
+        }
+    }
+
+    /// Connects to the first output port whose name contains `port_name`
+    /// and returns the full port name.
+    pub fn connect(&self, port_name: &str) -> Result<String, ShellError> {
+        let output = MidiOutput::new(&(env!("CARGO_PKG_NAME").to_owned() + " output"))
+            .map_err(|error| ShellError::MidiInit(error.to_string()))?;
+        let (port, full_name) = output
+            .ports()
+            .into_iter()
+            .find_map(|port| {
+                let name = output.port_name(&port).ok()?;
+                name.contains(port_name).then_some((port, name))
+            })
+            .ok_or_else(|| ShellError::MidiConnect {
+                port: port_name.to_owned(),
+                reason: "No matching MIDI output".to_owned(),
+            })?;
+
+        log::info!("Connecting to MIDI output {}", full_name);
+        let connection =
+            output
+                .connect(&port, &full_name)
+                .map_err(|error| ShellError::MidiConnect {
+                    port: full_name.clone(),
+                    reason: error.to_string(),
+                })?;
+
+        if let Ok(mut connections) = self.connections.lock() {
+            connections.push(connection);
+        }
+
+        Ok(full_name)
+    }
+}
+
+impl Drop for MidiOutputForwarder {
+    fn drop(&mut self) {
+        if let Some(thread) = self.thread.take() {
+            self.stop.store(true, Ordering::Release);
+            thread.join().ok();
+        }
+    }
+}
+
+/// Returns the names of all available MIDI output ports.
+pub(crate) fn midi_output_port_names() -> Vec<String> {
+    let Ok(output) = MidiOutput::new(&(env!("CARGO_PKG_NAME").to_owned() + " scan output")) else {
+        return Vec::new();
+    };
+
+    output
+        .ports()
+        .iter()
+        .filter_map(|port| output.port_name(port).ok())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Returns a clock running at one frame per microsecond, synchronized to a callback of
+    /// `frame_count` frames starting at `callback_start`.
+    fn clock(block_size: usize, frame_count: usize, callback_start: u64) -> BlockClock {
+        let mut clock = BlockClock::new(Instant::now(), 1_000_000, block_size);
+        clock.begin_callback(frame_count);
+        clock.callback_start = callback_start;
+        clock
+    }
+
+    #[test]
+    fn spreads_events_over_the_blocks_of_a_callback() {
+        let mut clock = clock(64, 1024, 10_000);
+
+        clock.begin_block(0);
+        assert!(clock.is_due(10_063));
+        assert!(!clock.is_due(10_064));
+        assert_eq!(clock.frame(10_010), 10);
+
+        clock.begin_block(64 * 5);
+        assert!(!clock.is_due(10_384));
+        assert_eq!(clock.frame(10_330), 10);
+    }
+
+    #[test]
+    fn keeps_frame_offsets_in_order() {
+        let mut clock = clock(64, 64, 10_000);
+
+        clock.begin_block(0);
+        assert_eq!(clock.frame(10_020), 20);
+        assert_eq!(clock.frame(10_010), 20);
+        assert_eq!(clock.frame(5_000), 20);
+        assert_eq!(clock.frame(20_000), 63);
+    }
+}
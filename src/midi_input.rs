@@ -0,0 +1,355 @@
+//! MIDI input connections with hot-plug detection.
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+use midir::{MidiInput, MidiInputConnection};
+
+use crate::midi::MidiProducer;
+use crate::{MidiEvent, MidiPortFilter, ShellError};
+
+/// Change of the connected MIDI input ports.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MidiPortEvent {
+    /// The port with the given name was connected.
+    Connected(String),
+
+    /// The port with the given name disappeared and was disconnected.
+    Disconnected(String),
+
+    /// MIDI could not be initialized or a port could not be connected.
+    /// Each failure is reported once, the shell continues without the affected ports.
+    Error(ShellError),
+}
+
+/// Port name together with the number of ports of the same name listed before it, so devices
+/// reporting identical names are told apart.
+type PortKey = (String, usize);
+
+/// Connections together with the keys of their ports.
+type Connections = Vec<(PortKey, MidiInputConnection<MidiInputHandler>)>;
+
+/// MIDI input connections, kept in sync with the available ports by a background thread.
+pub(crate) struct MidiInputs {
+    /// Connected ports.
+    connections: Arc<Mutex<Connections>>,
+
+    /// Receiver for the port changes.
+    events: mpsc::Receiver<MidiPortEvent>,
+
+    /// Flag signalling the thread to stop.
+    stop: Arc<AtomicBool>,
+
+    /// Rescanning thread.
+    thread: Option<JoinHandle<()>>,
+}
+
+impl MidiInputs {
+    /// Connects the MIDI inputs passing `filter` to the MIDI queue and starts rescanning.
+    /// - `start` is the reference point for the message timestamps.
+    /// - `rescan_interval` is the interval for rescanning the ports, `None` to scan only once.
+    pub fn new(
+        producer: MidiProducer,
+        start: Instant,
+        filter: MidiPortFilter,
+        rescan_interval: Option<Duration>,
+    ) -> Self {
+        let connections = Arc::new(Mutex::new(Connections::new()));
+        let stop = Arc::new(AtomicBool::new(false));
+        let (event_sender, events) = mpsc::channel();
+
+        let mut scanner = Scanner {
+            producer,
+            start,
+            filter,
+            connections: connections.clone(),
+            events: event_sender,
+            init_failed: false,
+            failed_ports: Vec::new(),
+            initial_ports: None,
+        };
+        scanner.rescan();
+
+        let thread = rescan_interval.map(|interval| {
+            let stop = stop.clone();
+            std::thread::spawn(move || loop {
+                std::thread::park_timeout(interval);
+
+                if stop.load(Ordering::Acquire) {
+                    break;
+                }
+
+                scanner.rescan();
+            })
+        });
+
+        Self {
+            connections,
+            events,
+            stop,
+            thread,
+        }
+    }
+
+    /// Returns the names of the connected ports.
+    pub fn port_names(&self) -> Vec<String> {
+        self.connections
+            .lock()
+            .map(|connections| {
+                connections
+                    .iter()
+                    .map(|((name, _), _)| name.clone())
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+
+    /// Returns the port changes since the last call.
+    pub fn events(&self) -> mpsc::TryIter<'_, MidiPortEvent> {
+        self.events.try_iter()
+    }
+}
+
+impl Drop for MidiInputs {
+    fn drop(&mut self) {
+        if let Some(thread) = self.thread.take() {
+            self.stop.store(true, Ordering::Release);
+            thread.thread().unpark();
+            thread.join().ok();
+        }
+    }
+}
+
+/// State of the rescanning thread.
+struct Scanner {
+    /// Producer passed to new connections.
+    producer: MidiProducer,
+
+    /// Reference point of the shell clock.
+    start: Instant,
+
+    /// Filter for the ports to connect.
+    filter: MidiPortFilter,
+
+    /// Connected ports.
+    connections: Arc<Mutex<Connections>>,
+
+    /// Sender for the port changes.
+    events: mpsc::Sender<MidiPortEvent>,
+
+    /// Flag for a reported initialization failure.
+    init_failed: bool,
+
+    /// Keys of the ports with reported connection failures.
+    failed_ports: Vec<PortKey>,
+
+    /// Keys of the ports available at the first scan, whose positions are matched by
+    /// [`crate::PortMatcher::Index`].
+    initial_ports: Option<Vec<PortKey>>,
+}
+
+impl Scanner {
+    /// Drops the connections of vanished ports and connects new ports passing the filter.
+    fn rescan(&mut self) {
+        let input = match MidiInput::new(&(env!("CARGO_PKG_NAME").to_owned() + " scan input")) {
+            Ok(input) => {
+                self.init_failed = false;
+                input
+            }
+            Err(error) => {
+                if !self.init_failed {
+                    self.init_failed = true;
+                    self.report(ShellError::MidiInit(error.to_string()));
+                }
+                return;
+            }
+        };
+
+        let ports = with_keys(
+            input
+                .ports()
+                .into_iter()
+                .filter_map(|port| input.port_name(&port).ok().map(|name| (name, port))),
+        );
+
+        // The positions change when devices are plugged in or out, so indices always refer to
+        // the ports of the first scan.
+        if self.initial_ports.is_none() {
+            self.initial_ports = Some(ports.iter().map(|(key, _)| key.clone()).collect());
+        }
+
+        self.failed_ports
+            .retain(|key| ports.iter().any(|(port_key, _)| port_key == key));
+
+        let connections = self.connections.clone();
+        let Ok(mut connections) = connections.lock() else {
+            return;
+        };
+
+        // If one of several ports with the same name vanishes, the last one is disconnected, as
+        // the ports can't be told apart otherwise.
+        connections.retain(|(key, _)| {
+            let present = ports.iter().any(|(port_key, _)| port_key == key);
+            if !present {
+                log::info!("MIDI input {} disconnected", key.0);
+                self.events
+                    .send(MidiPortEvent::Disconnected(key.0.clone()))
+                    .ok();
+            }
+            present
+        });
+
+        for (port_key, port) in ports.iter() {
+            if connections.iter().any(|(key, _)| key == port_key)
+                || self.failed_ports.contains(port_key)
+            {
+                continue;
+            }
+
+            let port_name = &port_key.0;
+            let index = self
+                .initial_ports
+                .iter()
+                .flatten()
+                .position(|key| key == port_key);
+            if !self.filter.accepts_port(index, port_name) {
+                log::debug!("Skipping MIDI input {}", port_name);
+                continue;
+            }
+
+            let input = match MidiInput::new(&(env!("CARGO_PKG_NAME").to_owned() + " input")) {
+                Ok(input) => input,
+                Err(error) => {
+                    self.report(ShellError::MidiInit(error.to_string()));
+                    return;
+                }
+            };
+
+            log::info!("Connecting to MIDI input {}", port_name);
+            let handler = MidiInputHandler {
+                producer: self.producer.clone(),
+                start: self.start,
+                timestamp_offset: None,
+            };
+
+            match input.connect(
+                port,
+                port_name.as_str(),
+                |timestamp, message, handler| handler.receive(timestamp, message),
+                handler,
+            ) {
+                Ok(connection) => {
+                    connections.push((port_key.clone(), connection));
+                    self.events
+                        .send(MidiPortEvent::Connected(port_name.clone()))
+                        .ok();
+                }
+                Err(error) => {
+                    self.failed_ports.push(port_key.clone());
+                    self.report(ShellError::MidiConnect {
+                        port: port_name.clone(),
+                        reason: error.to_string(),
+                    });
+                }
+            }
+        }
+    }
+
+    /// Logs an error and passes it to the event receiver.
+    fn report(&self, error: ShellError) {
+        log::error!("{}", error);
+        self.events.send(MidiPortEvent::Error(error)).ok();
+    }
+}
+
+/// Returns the ports with their keys, numbering the ports of equal names in their order.
+fn with_keys<T>(ports: impl IntoIterator<Item = (String, T)>) -> Vec<(PortKey, T)> {
+    let mut keyed_ports: Vec<(PortKey, T)> = Vec::new();
+
+    for (name, port) in ports {
+        let occurrence = keyed_ports
+            .iter()
+            .filter(|((port_name, _), _)| *port_name == name)
+            .count();
+        keyed_ports.push(((name, occurrence), port));
+    }
+
+    keyed_ports
+}
+
+/// State attached to each MIDI input connection.
+struct MidiInputHandler {
+    /// Producer for the received events.
+    producer: MidiProducer,
+
+    /// Reference point of the shell clock.
+    start: Instant,
+
+    /// Smallest observed difference between the shell clock and the port timestamps.
+    /// Used to translate port timestamps into shell time.
+    timestamp_offset: Option<i64>,
+}
+
+impl MidiInputHandler {
+    /// Converts a port timestamp into shell time and queues the message.
+    fn receive(&mut self, timestamp: u64, message: &[u8]) {
+        let now = self.start.elapsed().as_micros() as i64;
+        let offset = now - timestamp as i64;
+
+        // Messages are delivered with varying latency, the smallest one is the best estimate.
+        let offset = self.timestamp_offset.map_or(offset, |o| o.min(offset));
+        self.timestamp_offset = Some(offset);
+
+        let time = (timestamp as i64 + offset).max(0) as u64;
+
+        let Some(event) = MidiEvent::new(0, message) else {
+            log::warn!("Dropping MIDI message of {} bytes", message.len());
+            return;
+        };
+
+        // The lock is only shared between the input connections, never with the audio callback.
+        if let Ok(mut producer) = self.producer.lock() {
+            if producer.push((time, event)).is_err() {
+                log::warn!("MIDI queue full, dropping message");
+            }
+        }
+    }
+}
+
+/// Returns the names of all available MIDI input ports.
+pub(crate) fn midi_input_port_names() -> Vec<String> {
+    let Ok(input) = MidiInput::new(&(env!("CARGO_PKG_NAME").to_owned() + " scan input")) else {
+        return Vec::new();
+    };
+
+    input
+        .ports()
+        .iter()
+        .filter_map(|port| input.port_name(port).ok())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn numbers_ports_with_equal_names() {
+        let names = ["USB MIDI", "Keyboard", "USB MIDI"];
+        let keys: Vec<_> = with_keys(names.map(|name| (name.to_owned(), ())))
+            .into_iter()
+            .map(|(key, _)| key)
+            .collect();
+
+        assert_eq!(
+            keys,
+            [
+                ("USB MIDI".to_owned(), 0),
+                ("Keyboard".to_owned(), 0),
+                ("USB MIDI".to_owned(), 1),
+            ]
+        );
+    }
+}
@@ -0,0 +1,285 @@
+//! Decoding of raw MIDI messages.
+
+/// Decoded MIDI message.
+///
+/// Channels are zero-based, i.e. in the range `0..=15`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MidiMessage<'a> {
+    /// Note off.
+    NoteOff {
+        /// MIDI channel.
+        channel: u8,
+        /// Note number.
+        note: u8,
+        /// Release velocity.
+        velocity: u8,
+    },
+
+    /// Note on with a velocity greater than `0`.
+    /// Note on messages with velocity `0` are decoded as [`MidiMessage::NoteOff`]
+    /// with a release velocity of `64`.
+    NoteOn {
+        /// MIDI channel.
+        channel: u8,
+        /// Note number.
+        note: u8,
+        /// Velocity in the range `1..=127`.
+        velocity: u8,
+    },
+
+    /// Polyphonic key pressure.
+    PolyPressure {
+        /// MIDI channel.
+        channel: u8,
+        /// Note number.
+        note: u8,
+        /// Pressure value.
+        pressure: u8,
+    },
+
+    /// Control change.
+    ControlChange {
+        /// MIDI channel.
+        channel: u8,
+        /// Controller number.
+        controller: u8,
+        /// Controller value.
+        value: u8,
+    },
+
+    /// Program change.
+    ProgramChange {
+        /// MIDI channel.
+        channel: u8,
+        /// Program number.
+        program: u8,
+    },
+
+    /// Channel pressure.
+    Aftertouch {
+        /// MIDI channel.
+        channel: u8,
+        /// Pressure value.
+        pressure: u8,
+    },
+
+    /// Pitch bend.
+    PitchBend {
+        /// MIDI channel.
+        channel: u8,
+        /// Bend value in the range `-8192..=8191`, `0` is the center position.
+        value: i16,
+    },
+
+    /// System exclusive message, including the leading `0xF0` and trailing `0xF7` bytes.
+    SysEx(&'a [u8]),
+
+    /// MIDI time code quarter frame.
+    TimeCodeQuarterFrame(u8),
+
+    /// Song position pointer in MIDI beats.
+    SongPosition(u16),
+
+    /// Song select.
+    SongSelect(u8),
+
+    /// Tune request.
+    TuneRequest,
+
+    /// Timing clock, sent 24 times per quarter note.
+    TimingClock,
+
+    /// Start of the sequence.
+    Start,
+
+    /// Continuation of the sequence.
+    Continue,
+
+    /// Stop of the sequence.
+    Stop,
+
+    /// Active sensing.
+    ActiveSensing,
+
+    /// System reset.
+    SystemReset,
+}
+
+impl<'a> MidiMessage<'a> {
+    /// Decodes a raw message.
+    /// Returns `None` if the message is incomplete or has an unknown status byte.
+    ///
+    /// ```
+    /// use audio_midi_shell::MidiMessage;
+    ///
+    /// assert_eq!(
+    ///     MidiMessage::parse(&[0x91, 60, 0]),
+    ///     Some(MidiMessage::NoteOff { channel: 1, note: 60, velocity: 64 })
+    /// );
+    /// assert_eq!(MidiMessage::parse(&[0x90, 60]), None);
+    /// ```
+    pub fn parse(data: &'a [u8]) -> Option<Self> {
+        let status = *data.first()?;
+        let channel = status & 0x0F;
+
+        // Returns the data byte at `index`, failing for missing bytes or invalid values.
+        let data_byte = |index: usize| data.get(index).copied().filter(|byte| *byte < 0x80);
+
+        let message = match status & 0xF0 {
+            0x80 => Self::NoteOff {
+                channel,
+                note: data_byte(1)?,
+                velocity: data_byte(2)?,
+            },
+            0x90 => match data_byte(2)? {
+                0 => Self::NoteOff {
+                    channel,
+                    note: data_byte(1)?,
+                    velocity: 64,
+                },
+                velocity => Self::NoteOn {
+                    channel,
+                    note: data_byte(1)?,
+                    velocity,
+                },
+            },
+            0xA0 => Self::PolyPressure {
+                channel,
+                note: data_byte(1)?,
+                pressure: data_byte(2)?,
+            },
+            0xB0 => Self::ControlChange {
+                channel,
+                controller: data_byte(1)?,
+                value: data_byte(2)?,
+            },
+            0xC0 => Self::ProgramChange {
+                channel,
+                program: data_byte(1)?,
+            },
+            0xD0 => Self::Aftertouch {
+                channel,
+                pressure: data_byte(1)?,
+            },
+            0xE0 => {
+                let value = (data_byte(2)? as i16) << 7 | data_byte(1)? as i16;
+                Self::PitchBend {
+                    channel,
+                    value: value - 8192,
+                }
+            }
+            _ => match status {
+                0xF0 if data.last() == Some(&0xF7) => Self::SysEx(data),
+                0xF1 => Self::TimeCodeQuarterFrame(data_byte(1)?),
+                0xF2 => Self::SongPosition((data_byte(2)? as u16) << 7 | data_byte(1)? as u16),
+                0xF3 => Self::SongSelect(data_byte(1)?),
+                0xF6 => Self::TuneRequest,
+                0xF8 => Self::TimingClock,
+                0xFA => Self::Start,
+                0xFB => Self::Continue,
+                0xFC => Self::Stop,
+                0xFE => Self::ActiveSensing,
+                0xFF => Self::SystemReset,
+                _ => return None,
+            },
+        };
+
+        Some(message)
+    }
+
+    /// Returns the zero-based MIDI channel for channel messages, `None` for system messages.
+    pub fn channel(&self) -> Option<u8> {
+        match *self {
+            Self::NoteOff { channel, .. }
+            | Self::NoteOn { channel, .. }
+            | Self::PolyPressure { channel, .. }
+            | Self::ControlChange { channel, .. }
+            | Self::ProgramChange { channel, .. }
+            | Self::Aftertouch { channel, .. }
+            | Self::PitchBend { channel, .. } => Some(channel),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decodes_channel_messages() {
+        assert_eq!(
+            MidiMessage::parse(&[0x9F, 60, 100]),
+            Some(MidiMessage::NoteOn {
+                channel: 15,
+                note: 60,
+                velocity: 100
+            })
+        );
+        assert_eq!(
+            MidiMessage::parse(&[0xB0, 7, 127]),
+            Some(MidiMessage::ControlChange {
+                channel: 0,
+                controller: 7,
+                value: 127
+            })
+        );
+        assert_eq!(
+            MidiMessage::parse(&[0xC3, 5]),
+            Some(MidiMessage::ProgramChange {
+                channel: 3,
+                program: 5
+            })
+        );
+    }
+
+    #[test]
+    fn decodes_the_pitch_bend_range() {
+        let bend = |data: &[u8]| match MidiMessage::parse(data) {
+            Some(MidiMessage::PitchBend { value, .. }) => Some(value),
+            _ => None,
+        };
+
+        assert_eq!(bend(&[0xE0, 0x00, 0x00]), Some(-8192));
+        assert_eq!(bend(&[0xE0, 0x00, 0x40]), Some(0));
+        assert_eq!(bend(&[0xE0, 0x7F, 0x7F]), Some(8191));
+    }
+
+    #[test]
+    fn decodes_system_messages() {
+        let sysex = [0xF0, 0x7E, 0x7F, 0x06, 0x01, 0xF7];
+        assert_eq!(MidiMessage::parse(&sysex), Some(MidiMessage::SysEx(&sysex)));
+        assert_eq!(
+            MidiMessage::parse(&[0xF2, 0x01, 0x02]),
+            Some(MidiMessage::SongPosition(0x101))
+        );
+        assert_eq!(MidiMessage::parse(&[0xF8]), Some(MidiMessage::TimingClock));
+    }
+
+    #[test]
+    fn rejects_invalid_messages() {
+        // Empty and incomplete messages.
+        assert_eq!(MidiMessage::parse(&[]), None);
+        assert_eq!(MidiMessage::parse(&[0x80, 60]), None);
+        assert_eq!(MidiMessage::parse(&[0xE0, 0x00]), None);
+        assert_eq!(MidiMessage::parse(&[0xF0, 0x7E, 0x7F]), None);
+
+        // Data bytes with the high bit set.
+        assert_eq!(MidiMessage::parse(&[0x90, 0x80, 100]), None);
+        assert_eq!(MidiMessage::parse(&[0xB0, 7, 0xFF]), None);
+
+        // Running status and undefined status bytes.
+        assert_eq!(MidiMessage::parse(&[60, 100]), None);
+        assert_eq!(MidiMessage::parse(&[0xF4]), None);
+        assert_eq!(MidiMessage::parse(&[0xFD]), None);
+    }
+
+    #[test]
+    fn returns_the_channel_of_channel_messages() {
+        assert_eq!(
+            MidiMessage::parse(&[0xA5, 60, 10]).unwrap().channel(),
+            Some(5)
+        );
+        assert_eq!(MidiMessage::parse(&[0xFA]).unwrap().channel(), None);
+    }
+}
@@ -0,0 +1,471 @@
+//! Offline rendering and testing without an audio device.
+
+use std::path::Path;
+use std::time::Duration;
+
+use rtrb::Consumer;
+
+use crate::engine::{Engine, MAX_CHANNELS};
+use crate::midi::{midi_output_queue, MIDI_QUEUE_SIZE};
+use crate::{AudioMidiShell, AudioProcessor, InitContext, MidiEvent, Param, ShellError, WavInput};
+
+/// Sampling frequency in Hz of the [`TestShell`] unless specified otherwise.
+const DEFAULT_SAMPLE_RATE: u32 = 44100;
+
+impl AudioMidiShell {
+    /// Runs the generator without opening an audio device and writes the output
+    /// into a stereo 32-bit float WAV file. Processors receive silence as input.
+    /// - `sample_rate` is the sampling frequency in Hz.
+    /// - `block_size` is the number of samples for the `process` function.
+    /// - `duration` is the length of the rendered audio.
+    /// - `path` is the location of the WAV file to create.
+    ///
+    /// Returns an error if the sample rate is `0` or the file can't be written.
+    pub fn render_offline(
+        sample_rate: u32,
+        block_size: usize,
+        generator: impl AudioProcessor,
+        duration: Duration,
+        path: impl AsRef<Path>,
+    ) -> Result<(), ShellError> {
+        Self::render_offline_with_optional_input(
+            sample_rate,
+            block_size,
+            generator,
+            None,
+            duration,
+            path,
+        )
+    }
+
+    /// Runs the processor without opening an audio device with a WAV file as input signal and
+    /// writes the output into a stereo 32-bit float WAV file. The processor receives one input
+    /// per channel of the input file.
+    /// - `sample_rate` is the sampling frequency in Hz. The input file is resampled if its
+    ///   sampling frequency differs.
+    /// - `block_size` is the number of samples for the `process` function.
+    /// - `input` is the file played as input signal.
+    /// - `duration` is the length of the rendered audio.
+    /// - `path` is the location of the WAV file to create.
+    ///
+    /// Returns an error if the sample rate is `0`, the input file has more than
+    /// [`MAX_CHANNELS`] channels or the output file can't be written.
+    pub fn render_offline_with_input(
+        sample_rate: u32,
+        block_size: usize,
+        processor: impl AudioProcessor,
+        input: WavInput,
+        duration: Duration,
+        path: impl AsRef<Path>,
+    ) -> Result<(), ShellError> {
+        Self::render_offline_with_optional_input(
+            sample_rate,
+            block_size,
+            processor,
+            Some(input),
+            duration,
+            path,
+        )
+    }
+
+    /// Renders the processor offline, with silence as input signal if `input` is `None`.
+    fn render_offline_with_optional_input(
+        sample_rate: u32,
+        block_size: usize,
+        generator: impl AudioProcessor,
+        mut input: Option<WavInput>,
+        duration: Duration,
+        path: impl AsRef<Path>,
+    ) -> Result<(), ShellError> {
+        if sample_rate == 0 {
+            return Err(ShellError::UnsupportedSampleRate(sample_rate));
+        }
+
+        let input_channels = input.as_ref().map_or(0, WavInput::channels);
+        if input_channels > MAX_CHANNELS {
+            return Err(ShellError::UnsupportedChannels(input_channels));
+        }
+
+        let block_size = block_size.max(1);
+        let path = path.as_ref();
+        let error =
+            |error: hound::Error| ShellError::OutputFile(format!("{}: {}", path.display(), error));
+
+        let spec = hound::WavSpec {
+            channels: 2,
+            sample_rate,
+            bits_per_sample: 32,
+            sample_format: hound::SampleFormat::Float,
+        };
+        let mut writer = hound::WavWriter::create(path, spec).map_err(error)?;
+
+        // MIDI messages sent by the generator are discarded.
+        let (midi_sender, _) = midi_output_queue(MIDI_QUEUE_SIZE);
+        let mut engine = Engine::new(
+            generator,
+            &InitContext::new(sample_rate, block_size, 2, input_channels),
+            midi_sender,
+        );
+        if let Some(input) = input.as_mut() {
+            input.resample(sample_rate);
+        }
+        let mut frames_remaining = (duration.as_secs_f64() * sample_rate as f64).round() as usize;
+
+        while frames_remaining > 0 {
+            if let Some(input) = input.as_mut() {
+                input.read(engine.inputs_mut());
+            }
+            engine.process();
+
+            let frame_count = frames_remaining.min(block_size);
+            let [samples_left, samples_right] = engine.outputs() else {
+                unreachable!()
+            };
+
+            for (sample_left, sample_right) in samples_left[..frame_count]
+                .iter()
+                .zip(samples_right[..frame_count].iter())
+            {
+                writer.write_sample(*sample_left).map_err(error)?;
+                writer.write_sample(*sample_right).map_err(error)?;
+            }
+
+            frames_remaining -= frame_count;
+        }
+
+        // Calls `deinit` on the generator.
+        engine.into_processor();
+
+        writer.finalize().map_err(error)
+    }
+}
+
+/// Deterministic harness for running a generator with scripted MIDI input.
+///
+/// ```
+/// use audio_midi_shell::{AudioGenerator, TestShell};
+///
+/// struct Gate(f32);
+///
+/// impl AudioGenerator for Gate {
+///     fn process(&mut self, samples_left: &mut [f32], samples_right: &mut [f32]) {
+///         samples_left.fill(self.0);
+///         samples_right.fill(self.0);
+///     }
+///
+///     fn process_midi(&mut self, message: &[u8]) {
+///         self.0 = if message[0] & 0xF0 == 0x90 { 1.0 } else { 0.0 };
+///     }
+/// }
+///
+/// let mut shell = TestShell::new(64, Gate(0.0));
+/// shell.schedule_midi(128, &[0x90, 60, 100]);
+///
+/// let output = shell.run(4);
+/// assert_eq!(output.left()[127], 0.0);
+/// assert_eq!(output.left()[128], 1.0);
+/// ```
+pub struct TestShell<G> {
+    /// Engine running the generator.
+    engine: Engine<G>,
+
+    /// Number of samples per block.
+    block_size: usize,
+
+    /// Frame position of the next block.
+    position: usize,
+
+    /// Scheduled MIDI events with their frame positions, sorted by position.
+    midi_messages: Vec<(usize, MidiEvent)>,
+
+    /// Consumer for the MIDI messages sent by the processor.
+    midi_output: Consumer<MidiEvent>,
+}
+
+impl<G: AudioProcessor> TestShell<G> {
+    /// Initializes the generator and returns a new stereo harness running at 44.1 kHz.
+    /// - `block_size` is the number of samples for the `process` function.
+    pub fn new(block_size: usize, generator: G) -> Self {
+        Self::with_channels(block_size, 2, 2, generator)
+    }
+
+    /// Initializes the processor and returns a new harness with the given channel counts running
+    /// at 44.1 kHz.
+    /// - `block_size` is the number of samples for the `process` function.
+    /// - `output_channels` is the number of output channels.
+    /// - `input_channels` is the number of input channels.
+    ///
+    /// Panics if `output_channels` is `0` or a channel count exceeds
+    /// [`MAX_CHANNELS`](crate::MAX_CHANNELS).
+    pub fn with_channels(
+        block_size: usize,
+        output_channels: usize,
+        input_channels: usize,
+        processor: G,
+    ) -> Self {
+        Self::with_sample_rate(
+            DEFAULT_SAMPLE_RATE,
+            block_size,
+            output_channels,
+            input_channels,
+            processor,
+        )
+    }
+
+    /// Initializes the processor and returns a new harness with the given sampling frequency and
+    /// channel counts.
+    /// - `sample_rate` is the sampling frequency in Hz passed to `init_with_context`.
+    /// - `block_size` is the number of samples for the `process` function.
+    /// - `output_channels` is the number of output channels.
+    /// - `input_channels` is the number of input channels.
+    ///
+    /// Panics if `output_channels` is `0` or a channel count exceeds
+    /// [`MAX_CHANNELS`](crate::MAX_CHANNELS).
+    pub fn with_sample_rate(
+        sample_rate: u32,
+        block_size: usize,
+        output_channels: usize,
+        input_channels: usize,
+        processor: G,
+    ) -> Self {
+        let block_size = block_size.max(1);
+        let (midi_sender, midi_output) = midi_output_queue(MIDI_QUEUE_SIZE);
+        let context = InitContext::new(sample_rate, block_size, output_channels, input_channels);
+
+        Self {
+            engine: Engine::new(processor, &context, midi_sender),
+            block_size,
+            position: 0,
+            midi_messages: Vec::new(),
+            midi_output,
+        }
+    }
+
+    /// Returns the harness with the blocks split at the frame positions of the scheduled MIDI
+    /// messages, see [`AudioMidiShellBuilder::split_at_midi_events`](crate::AudioMidiShellBuilder::split_at_midi_events).
+    pub fn split_at_midi_events(mut self) -> Self {
+        self.engine.split_at_midi_events(MIDI_QUEUE_SIZE);
+        self
+    }
+
+    /// Schedules a MIDI message for delivery.
+    /// - `frame` is the absolute frame position counted from the start of the harness.
+    ///
+    /// Messages are passed to `process_midi_event` before the block containing their frame
+    /// position, with the frame offset into that block. With split blocks, they are passed at their
+    /// exact position instead. Messages scheduled for a position that was
+    /// already processed are delivered at the start of the next block.
+    ///
+    /// Panics if the message exceeds [`MAX_MESSAGE_SIZE`](crate::MAX_MESSAGE_SIZE).
+    pub fn schedule_midi(&mut self, frame: usize, message: &[u8]) {
+        let event = MidiEvent::new(0, message).expect("MIDI message too long");
+        let index = self.midi_messages.partition_point(|(f, _)| *f <= frame);
+        self.midi_messages.insert(index, (frame, event));
+    }
+
+    /// Runs the generator for a number of blocks and returns the captured output.
+    pub fn run(&mut self, block_count: usize) -> CapturedOutput {
+        self.run_with_input(block_count, &[])
+    }
+
+    /// Runs the processor for a number of blocks with the given input signal and returns the
+    /// captured output.
+    /// - `inputs` contains the samples for each input channel. Missing channels and inputs
+    ///   shorter than the processed length are padded with silence.
+    pub fn run_with_input(&mut self, block_count: usize, inputs: &[&[f32]]) -> CapturedOutput {
+        let mut output = CapturedOutput {
+            channels: vec![
+                Vec::with_capacity(block_count * self.block_size);
+                self.engine.outputs().len()
+            ],
+            midi: Vec::new(),
+        };
+
+        for block_no in 0..block_count {
+            let block_end = self.position + self.block_size;
+            let due_count = self
+                .midi_messages
+                .partition_point(|(frame, _)| *frame < block_end);
+
+            for (frame, event) in self.midi_messages.drain(..due_count) {
+                let offset = frame.saturating_sub(self.position);
+                self.engine.process_midi_event(event.with_frame(offset));
+            }
+
+            let block_start = block_no * self.block_size;
+            for (channel_no, buffer) in self.engine.inputs_mut().iter_mut().enumerate() {
+                let signal = inputs.get(channel_no).copied().unwrap_or_default();
+                for (frame_no, sample) in buffer.iter_mut().enumerate() {
+                    *sample = signal.get(block_start + frame_no).copied().unwrap_or(0.0);
+                }
+            }
+
+            self.engine.process();
+
+            for (captured, buffer) in output.channels.iter_mut().zip(self.engine.outputs()) {
+                captured.extend_from_slice(buffer);
+            }
+
+            while let Ok(event) = self.midi_output.pop() {
+                output
+                    .midi
+                    .push((self.position + event.frame(), event.data().to_vec()));
+            }
+
+            self.position = block_end;
+        }
+
+        output
+    }
+
+    /// Returns the frame position of the next block.
+    pub fn position(&self) -> usize {
+        self.position
+    }
+
+    /// Returns the parameters declared by the generator.
+    pub fn params(&self) -> &[Param] {
+        self.engine.params()
+    }
+
+    /// Deinitializes the generator and returns it.
+    pub fn stop(self) -> G {
+        self.engine.into_processor()
+    }
+
+    /// Returns a reference to the generator.
+    pub fn generator(&self) -> &G {
+        self.engine.processor()
+    }
+
+    /// Returns a mutable reference to the generator.
+    pub fn generator_mut(&mut self) -> &mut G {
+        self.engine.processor_mut()
+    }
+}
+
+/// Output captured by the [`TestShell`].
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct CapturedOutput {
+    /// Samples of each output channel.
+    pub channels: Vec<Vec<f32>>,
+
+    /// MIDI messages sent by the processor with their absolute frame positions.
+    pub midi: Vec<(usize, Vec<u8>)>,
+}
+
+impl CapturedOutput {
+    /// Returns the samples of the first channel.
+    pub fn left(&self) -> &[f32] {
+        &self.channels[0]
+    }
+
+    /// Returns the samples of the second channel, or the first one for mono output.
+    pub fn right(&self) -> &[f32] {
+        self.channels.get(1).unwrap_or(&self.channels[0])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::MidiSender;
+
+    /// Processor passing its inputs through.
+    struct Thru;
+
+    impl AudioProcessor for Thru {
+        fn process(&mut self, inputs: &[&[f32]], outputs: &mut [&mut [f32]]) {
+            for (output, input) in outputs.iter_mut().zip(inputs) {
+                output.copy_from_slice(input);
+            }
+        }
+    }
+
+    /// Processor echoing MIDI events and sending a clock at the second frame of each buffer.
+    #[derive(Default)]
+    struct Echo(Option<MidiSender>);
+
+    impl AudioProcessor for Echo {
+        fn process(&mut self, _: &[&[f32]], _: &mut [&mut [f32]]) {
+            if let Some(sender) = self.0.as_mut() {
+                sender.send(1, &[0xF8]);
+            }
+        }
+
+        fn process_midi_event(&mut self, event: MidiEvent) {
+            if let Some(sender) = self.0.as_mut() {
+                sender.send(event.frame(), event.data());
+            }
+        }
+
+        fn init_midi_output(&mut self, sender: MidiSender) {
+            self.0 = Some(sender);
+        }
+    }
+
+    #[test]
+    fn captures_midi_output_at_absolute_positions() {
+        let mut shell = TestShell::new(64, Echo::default());
+        shell.schedule_midi(74, &[0x90, 60, 100]);
+        let output = shell.run(2);
+
+        assert_eq!(
+            output.midi,
+            [(1, vec![0xF8]), (74, vec![0x90, 60, 100]), (65, vec![0xF8]),]
+        );
+    }
+
+    #[test]
+    fn captures_midi_output_at_absolute_positions_with_split_blocks() {
+        let mut shell = TestShell::new(64, Echo::default()).split_at_midi_events();
+        shell.schedule_midi(74, &[0x90, 60, 100]);
+        let output = shell.run(2);
+
+        assert_eq!(
+            output.midi,
+            [
+                (1, vec![0xF8]),
+                (65, vec![0xF8]),
+                (74, vec![0x90, 60, 100]),
+                (75, vec![0xF8]),
+            ]
+        );
+    }
+
+    #[test]
+    fn rejects_input_files_with_too_many_channels() {
+        let directory = std::env::temp_dir();
+        let input_path = directory.join("audio-midi-shell-too-many-channels.wav");
+        let output_path = directory.join("audio-midi-shell-too-many-channels-output.wav");
+
+        let spec = hound::WavSpec {
+            channels: MAX_CHANNELS as u16 + 1,
+            sample_rate: 44100,
+            bits_per_sample: 32,
+            sample_format: hound::SampleFormat::Float,
+        };
+        let mut writer = hound::WavWriter::create(&input_path, spec).unwrap();
+        for _ in 0..spec.channels {
+            writer.write_sample(0.0f32).unwrap();
+        }
+        writer.finalize().unwrap();
+
+        let input = WavInput::open(&input_path).unwrap();
+        let result = AudioMidiShell::render_offline_with_input(
+            44100,
+            64,
+            Thru,
+            input,
+            Duration::from_millis(10),
+            &output_path,
+        );
+        std::fs::remove_file(&input_path).ok();
+
+        assert!(matches!(
+            result,
+            Err(ShellError::UnsupportedChannels(channels)) if channels == MAX_CHANNELS + 1
+        ));
+        assert!(!output_path.exists());
+    }
+}
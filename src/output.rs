@@ -0,0 +1,32 @@
+//! Audio output device.
+
+use cpal::traits::{DeviceTrait, HostTrait};
+
+use crate::ShellError;
+
+/// Checks that the default output device supports `sample_rate` for `channels` channels.
+///
+/// The device is opened by the output backend afterwards, which would otherwise silently fall
+/// back to the nearest supported rate. Passes if the supported configurations can't be queried,
+/// so the backend reports the actual problem when opening the device.
+pub(crate) fn check_sample_rate(sample_rate: u32, channels: usize) -> Result<(), ShellError> {
+    let Some(device) = cpal::default_host().default_output_device() else {
+        return Ok(());
+    };
+    let Ok(configs) = device.supported_output_configs() else {
+        return Ok(());
+    };
+
+    let configs: Vec<_> = configs.collect();
+    let supported = configs.is_empty()
+        || configs.iter().any(|config| {
+            config.channels() as usize >= channels
+                && (config.min_sample_rate().0..=config.max_sample_rate().0).contains(&sample_rate)
+        });
+
+    if supported {
+        Ok(())
+    } else {
+        Err(ShellError::UnsupportedSampleRate(sample_rate))
+    }
+}
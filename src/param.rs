@@ -0,0 +1,164 @@
+//! Named parameters shared between the main thread and the audio callback.
+
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::Arc;
+
+/// Named parameter with a value range, readable and writable from any thread without locking.
+///
+/// Clones share the same value, so a generator can keep one handle and return clones from
+/// `params`. The shell passes these to the application and sets the values of parameters mapped
+/// to a MIDI controller when a matching control change message is received, before it is passed
+/// to the generator.
+///
+/// ```
+/// use audio_midi_shell::{AudioGenerator, Param, TestShell};
+///
+/// struct Level {
+///     level: Param,
+/// }
+///
+/// impl AudioGenerator for Level {
+///     fn process(&mut self, samples_left: &mut [f32], samples_right: &mut [f32]) {
+///         samples_left.fill(self.level.get());
+///         samples_right.fill(self.level.get());
+///     }
+///
+///     fn params(&self) -> Vec<Param> {
+///         vec![self.level.clone()]
+///     }
+/// }
+///
+/// let level = Param::new("Level", 0.0, 1.0, 0.5).with_cc(7);
+/// let mut shell = TestShell::new(64, Level { level: level.clone() });
+/// assert_eq!(shell.run(1).left()[0], 0.5);
+///
+/// level.set(0.25);
+/// assert_eq!(shell.run(1).left()[0], 0.25);
+///
+/// shell.schedule_midi(128, &[0xB0, 7, 127]);
+/// assert_eq!(shell.run(1).left()[0], 1.0);
+/// ```
+#[derive(Debug, Clone)]
+pub struct Param {
+    /// Name of the parameter.
+    name: String,
+
+    /// Unit of the value, e.g. `Hz` or `dB`.
+    unit: String,
+
+    /// Minimum value.
+    min: f32,
+
+    /// Maximum value.
+    max: f32,
+
+    /// Initial value.
+    default: f32,
+
+    /// MIDI controller number mapped to the parameter.
+    cc: Option<u8>,
+
+    /// Bits of the current value.
+    value: Arc<AtomicU32>,
+}
+
+impl Param {
+    /// Returns a new parameter.
+    /// - `name` is used to look up the parameter in the shell.
+    /// - `min` and `max` define the value range, they are swapped if `min` is greater.
+    /// - `default` is the initial value, clamped to the range.
+    ///
+    /// Panics if `min` or `max` is NaN.
+    pub fn new(name: &str, min: f32, max: f32, default: f32) -> Self {
+        assert!(
+            !min.is_nan() && !max.is_nan(),
+            "Parameter range must not be NaN"
+        );
+
+        let (min, max) = if min <= max { (min, max) } else { (max, min) };
+        let default = default.clamp(min, max);
+
+        Self {
+            name: name.to_owned(),
+            unit: String::new(),
+            min,
+            max,
+            default,
+            cc: None,
+            value: Arc::new(AtomicU32::new(default.to_bits())),
+        }
+    }
+
+    /// Returns the parameter with a unit for display, e.g. `Hz` or `dB`.
+    pub fn with_unit(mut self, unit: &str) -> Self {
+        self.unit = unit.to_owned();
+        self
+    }
+
+    /// Returns the parameter mapped to the MIDI controller `controller` on all channels.
+    /// Controller values `0..=127` are scaled linearly to the value range.
+    pub fn with_cc(mut self, controller: u8) -> Self {
+        self.cc = Some(controller);
+        self
+    }
+
+    /// Returns the name.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Returns the unit, empty if none was set.
+    pub fn unit(&self) -> &str {
+        &self.unit
+    }
+
+    /// Returns the minimum value.
+    pub fn min(&self) -> f32 {
+        self.min
+    }
+
+    /// Returns the maximum value.
+    pub fn max(&self) -> f32 {
+        self.max
+    }
+
+    /// Returns the initial value.
+    pub fn default_value(&self) -> f32 {
+        self.default
+    }
+
+    /// Returns the mapped MIDI controller number.
+    pub fn cc(&self) -> Option<u8> {
+        self.cc
+    }
+
+    /// Returns the current value.
+    pub fn get(&self) -> f32 {
+        f32::from_bits(self.value.load(Ordering::Relaxed))
+    }
+
+    /// Sets the value, clamped to the range.
+    pub fn set(&self, value: f32) {
+        let value = value.clamp(self.min, self.max);
+        self.value.store(value.to_bits(), Ordering::Relaxed);
+    }
+
+    /// Returns the current value scaled to `0.0..=1.0`.
+    pub fn normalized(&self) -> f32 {
+        if self.max > self.min {
+            (self.get() - self.min) / (self.max - self.min)
+        } else {
+            0.0
+        }
+    }
+
+    /// Sets the value from a position in `0.0..=1.0` within the range.
+    pub fn set_normalized(&self, value: f32) {
+        self.set(self.min + value.clamp(0.0, 1.0) * (self.max - self.min));
+    }
+
+    /// Sets the value back to the initial value.
+    pub fn reset(&self) {
+        self.set(self.default);
+    }
+}
@@ -0,0 +1,178 @@
+//! Selection of MIDI ports.
+
+/// Criterion for matching a MIDI port.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PortMatcher {
+    /// Matches the exact port name.
+    Name(String),
+
+    /// Matches port names containing the string.
+    Contains(String),
+
+    /// Matches port names against a wildcard pattern,
+    /// where `*` matches any sequence of characters and `?` matches a single character.
+    Pattern(String),
+
+    /// Matches the port at the given position in the list of available ports.
+    ///
+    /// The index refers to the ports available when the shell starts, as the positions shift
+    /// when devices are plugged in or out. The selected port is reconnected by its name, ports
+    /// appearing later are never matched by an index. Ports with equal names are told apart by
+    /// their order.
+    Index(usize),
+}
+
+impl PortMatcher {
+    /// Returns if the port at `index` with the name `name` is matched.
+    pub fn matches(&self, index: usize, name: &str) -> bool {
+        self.matches_port(Some(index), name)
+    }
+
+    /// Returns if the port with the name `name` is matched.
+    /// - `index` is the position of the port, `None` if it has no stable position.
+    fn matches_port(&self, index: Option<usize>, name: &str) -> bool {
+        match self {
+            Self::Name(n) => name == n,
+            Self::Contains(s) => name.contains(s.as_str()),
+            Self::Pattern(p) => wildcard_match(p, name),
+            Self::Index(i) => index == Some(*i),
+        }
+    }
+}
+
+/// Filter deciding which MIDI ports are connected.
+///
+/// A port is connected if it matches any of the included matchers, or if there are none,
+/// and matches none of the excluded matchers. The default filter connects all ports.
+///
+/// ```
+/// use audio_midi_shell::{MidiPortFilter, PortMatcher};
+///
+/// let filter = MidiPortFilter::default()
+///     .exclude(PortMatcher::Contains("Midi Through".into()));
+///
+/// assert!(filter.accepts(0, "USB Keyboard"));
+/// assert!(!filter.accepts(1, "Midi Through:Midi Through Port-0 14:0"));
+///
+/// let filter = MidiPortFilter::default().include(PortMatcher::Pattern("USB*Key?oard".into()));
+/// assert!(filter.accepts(0, "USB Keyboard"));
+/// assert!(!filter.accepts(0, "USB Keyboard 2"));
+/// ```
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct MidiPortFilter {
+    /// Matchers for the ports to connect.
+    include: Vec<PortMatcher>,
+
+    /// Matchers for the ports to skip.
+    exclude: Vec<PortMatcher>,
+}
+
+impl MidiPortFilter {
+    /// Returns the filter with an additional matcher for ports to connect.
+    pub fn include(mut self, matcher: PortMatcher) -> Self {
+        self.include.push(matcher);
+        self
+    }
+
+    /// Returns the filter with an additional matcher for ports to skip.
+    pub fn exclude(mut self, matcher: PortMatcher) -> Self {
+        self.exclude.push(matcher);
+        self
+    }
+
+    /// Returns if the port at `index` with the name `name` passes the filter.
+    pub fn accepts(&self, index: usize, name: &str) -> bool {
+        self.accepts_port(Some(index), name)
+    }
+
+    /// Returns if the port with the name `name` passes the filter.
+    /// - `index` is the position of the port, `None` if it has no stable position.
+    pub(crate) fn accepts_port(&self, index: Option<usize>, name: &str) -> bool {
+        let included = self.include.is_empty()
+            || self
+                .include
+                .iter()
+                .any(|matcher| matcher.matches_port(index, name));
+        let excluded = self
+            .exclude
+            .iter()
+            .any(|matcher| matcher.matches_port(index, name));
+
+        included && !excluded
+    }
+}
+
+/// Matches `text` against a pattern containing `*` and `?` wildcards.
+fn wildcard_match(pattern: &str, text: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let text: Vec<char> = text.chars().collect();
+
+    let (mut p, mut t) = (0, 0);
+    let mut backtrack: Option<(usize, usize)> = None;
+
+    while t < text.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                backtrack = Some((p, t));
+                p += 1;
+            }
+            Some(c) if *c == '?' || *c == text[t] => {
+                p += 1;
+                t += 1;
+            }
+            _ => match backtrack {
+                // Let the last `*` consume one more character.
+                Some((star_p, star_t)) => {
+                    p = star_p + 1;
+                    t = star_t + 1;
+                    backtrack = Some((star_p, star_t + 1));
+                }
+                None => return false,
+            },
+        }
+    }
+
+    pattern[p..].iter().all(|c| *c == '*')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn matches_wildcards() {
+        assert!(wildcard_match("USB*", "USB Keyboard"));
+        assert!(wildcard_match("*Keyboard", "USB Keyboard"));
+        assert!(wildcard_match("U?B*rd", "USB Keyboard"));
+        assert!(wildcard_match("*", ""));
+        assert!(wildcard_match("**a**", "a"));
+        assert!(wildcard_match("", ""));
+
+        assert!(!wildcard_match("", "USB"));
+        assert!(!wildcard_match("USB?", "USB"));
+        assert!(!wildcard_match("*Key", "USB Keyboard"));
+        assert!(!wildcard_match("usb*", "USB Keyboard"));
+    }
+
+    #[test]
+    fn backtracks_to_the_last_wildcard() {
+        assert!(wildcard_match("*a*b", "aaab"));
+        assert!(wildcard_match("*ab*ab", "xabyabab"));
+        assert!(wildcard_match("a*b?d", "abxbcd"));
+        assert!(!wildcard_match("*a*b", "aaabc"));
+    }
+
+    #[test]
+    fn matches_multibyte_characters() {
+        assert!(wildcard_match("Ger?t*", "Gerät 1"));
+    }
+
+    #[test]
+    fn matches_indices_only_with_a_stable_position() {
+        let filter = MidiPortFilter::default().include(PortMatcher::Index(1));
+
+        assert!(filter.accepts_port(Some(1), "Keyboard"));
+        assert!(!filter.accepts_port(Some(0), "Keyboard"));
+        assert!(!filter.accepts_port(None, "Keyboard"));
+    }
+}
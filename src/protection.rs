@@ -0,0 +1,324 @@
+//! Safety stage protecting the output from invalid or excessive samples.
+
+use std::sync::Mutex;
+use std::time::Duration;
+
+use rtrb::{Consumer, Producer, RingBuffer};
+
+use crate::MAX_CHANNELS;
+
+/// Number of events that can be queued for the application.
+const EVENT_QUEUE_SIZE: usize = 64;
+
+/// Cutoff frequency of the DC blocker in Hz.
+const DC_BLOCKER_CUTOFF: f32 = 10.0;
+
+/// Configuration of the output protection.
+///
+/// The protection runs after the generator and before the samples are written to the device.
+/// Blocks containing NaN or infinite samples are muted, denormal samples are flushed to zero.
+/// Optionally, DC offsets are removed and the level is limited to a ceiling.
+///
+/// ```no_run
+/// use audio_midi_shell::{AudioMidiShell, OutputProtection};
+/// # struct Synth;
+/// # impl audio_midi_shell::AudioGenerator for Synth {
+/// #     fn process(&mut self, _: &mut [f32], _: &mut [f32]) {}
+/// # }
+///
+/// let shell = AudioMidiShell::builder()
+///     .output_protection(OutputProtection::default().ceiling(Some(0.5)))
+///     .spawn(Synth)
+///     .unwrap();
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct OutputProtection {
+    /// Flag for removing DC offsets.
+    dc_blocking: bool,
+
+    /// Maximum absolute sample value.
+    ceiling: Option<f32>,
+
+    /// Time for the limiter gain to recover.
+    release: Duration,
+}
+
+impl Default for OutputProtection {
+    /// Returns the protection with DC blocking and a ceiling of `1.0`.
+    fn default() -> Self {
+        Self {
+            dc_blocking: true,
+            ceiling: Some(1.0),
+            release: Duration::from_millis(100),
+        }
+    }
+}
+
+impl OutputProtection {
+    /// Returns the protection with DC blocking enabled or disabled.
+    pub fn dc_blocking(mut self, enabled: bool) -> Self {
+        self.dc_blocking = enabled;
+        self
+    }
+
+    /// Returns the protection with the maximum absolute sample value, `None` to disable the
+    /// limiter. Louder passages are attenuated without overshoot. NaN or infinite values also
+    /// disable the limiter.
+    pub fn ceiling(mut self, ceiling: Option<f32>) -> Self {
+        self.ceiling = ceiling.map(f32::abs).filter(|ceiling| ceiling.is_finite());
+        self
+    }
+
+    /// Returns the protection with the time for the limiter gain to recover.
+    pub fn release(mut self, release: Duration) -> Self {
+        self.release = release;
+        self
+    }
+}
+
+/// Problem detected by the output protection.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProtectionEvent {
+    /// The generator produced NaN or infinite samples, the output was muted until valid samples
+    /// were produced again.
+    NonFinite,
+
+    /// The generator produced denormal samples, which were flushed to zero.
+    /// These indicate a decaying signal that can cause high CPU load.
+    Denormal,
+}
+
+/// Creates the stage applied in the audio callback and the receiver for its events.
+pub(crate) fn output_protection(
+    config: OutputProtection,
+    sample_rate: u32,
+) -> (ProtectionEvents, Protector) {
+    let (producer, consumer) = RingBuffer::new(EVENT_QUEUE_SIZE);
+    let sample_rate = sample_rate as f32;
+    let release_frames = (config.release.as_secs_f32() * sample_rate).max(1.0);
+
+    let events = ProtectionEvents {
+        consumer: Mutex::new(consumer),
+    };
+    let protector = Protector {
+        config,
+        events: producer,
+        // Kept in the stable range for sample rates too low for the cutoff frequency.
+        dc_coefficient: (1.0 - std::f32::consts::TAU * DC_BLOCKER_CUTOFF / sample_rate)
+            .clamp(0.0, 1.0),
+        dc_states: [(0.0, 0.0); MAX_CHANNELS],
+        gain: 1.0,
+        release_coefficient: 1.0 - 1.0 / release_frames,
+        non_finite: false,
+        denormal: false,
+    };
+
+    (events, protector)
+}
+
+/// Receiver for the events of the output protection.
+pub(crate) struct ProtectionEvents {
+    /// Consumer for the events, only locked by the application.
+    consumer: Mutex<Consumer<ProtectionEvent>>,
+}
+
+impl ProtectionEvents {
+    /// Returns the events since the last call.
+    pub fn take(&self) -> Vec<ProtectionEvent> {
+        let Ok(mut consumer) = self.consumer.lock() else {
+            return Vec::new();
+        };
+
+        std::iter::from_fn(|| consumer.pop().ok()).collect()
+    }
+}
+
+/// Output protection applied in the audio callback.
+pub(crate) struct Protector {
+    /// Configuration.
+    config: OutputProtection,
+
+    /// Producer for the events.
+    events: Producer<ProtectionEvent>,
+
+    /// Feedback coefficient of the DC blocker.
+    dc_coefficient: f32,
+
+    /// Previous input and output of the DC blocker per channel.
+    dc_states: [(f32, f32); MAX_CHANNELS],
+
+    /// Current gain of the limiter.
+    gain: f32,
+
+    /// Coefficient for the recovery of the limiter gain per frame.
+    release_coefficient: f32,
+
+    /// Flag for muted output due to non-finite samples.
+    non_finite: bool,
+
+    /// Flag for denormal samples in the previous block.
+    denormal: bool,
+}
+
+impl Protector {
+    /// Processes the output buffers of a block in place.
+    pub fn process(&mut self, outputs: &mut [Vec<f32>]) {
+        let non_finite = outputs
+            .iter()
+            .any(|buffer| buffer.iter().any(|sample| !sample.is_finite()));
+
+        if non_finite {
+            if !self.non_finite {
+                self.events.push(ProtectionEvent::NonFinite).ok();
+            }
+            self.non_finite = true;
+
+            outputs.iter_mut().for_each(|buffer| buffer.fill(0.0));
+            self.dc_states = [(0.0, 0.0); MAX_CHANNELS];
+            self.gain = 1.0;
+            return;
+        }
+        self.non_finite = false;
+
+        let mut denormal = false;
+        for sample in outputs.iter_mut().flat_map(|buffer| buffer.iter_mut()) {
+            if sample.is_subnormal() {
+                *sample = 0.0;
+                denormal = true;
+            }
+        }
+        if denormal && !self.denormal {
+            self.events.push(ProtectionEvent::Denormal).ok();
+        }
+        self.denormal = denormal;
+
+        if self.config.dc_blocking {
+            self.block_dc(outputs);
+        }
+
+        if let Some(ceiling) = self.config.ceiling {
+            self.limit(outputs, ceiling);
+        }
+    }
+
+    /// Removes DC offsets with a first order highpass filter.
+    fn block_dc(&mut self, outputs: &mut [Vec<f32>]) {
+        for (buffer, (previous_input, previous_output)) in
+            outputs.iter_mut().zip(self.dc_states.iter_mut())
+        {
+            for sample in buffer.iter_mut() {
+                let input = *sample;
+                let mut output = input - *previous_input + self.dc_coefficient * *previous_output;
+
+                // Keep the decaying filter state from becoming denormal.
+                if output.abs() < f32::MIN_POSITIVE {
+                    output = 0.0;
+                }
+
+                *previous_input = input;
+                *previous_output = output;
+                *sample = output;
+            }
+        }
+    }
+
+    /// Attenuates all channels equally, so no sample exceeds `ceiling`.
+    fn limit(&mut self, outputs: &mut [Vec<f32>], ceiling: f32) {
+        let frame_count = outputs.first().map_or(0, Vec::len);
+
+        for frame_no in 0..frame_count {
+            let peak = outputs
+                .iter()
+                .map(|buffer| buffer[frame_no].abs())
+                .fold(0.0, f32::max);
+
+            self.gain = 1.0 - (1.0 - self.gain) * self.release_coefficient;
+            if peak * self.gain > ceiling {
+                self.gain = ceiling / peak;
+            }
+
+            for buffer in outputs.iter_mut() {
+                let sample = &mut buffer[frame_no];
+                *sample = (*sample * self.gain).clamp(-ceiling, ceiling);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Returns a protector at 1 kHz with a release time of 100 frames.
+    fn protector(config: OutputProtection) -> (ProtectionEvents, Protector) {
+        output_protection(config.release(Duration::from_millis(100)), 1000)
+    }
+
+    #[test]
+    fn limits_all_channels_equally() {
+        let (_, mut protector) = protector(OutputProtection::default());
+        let mut outputs = vec![vec![1.0, 0.2], vec![-0.5, 0.1]];
+        protector.limit(&mut outputs, 0.5);
+
+        assert_eq!(outputs[0][0], 0.5);
+        assert_eq!(outputs[1][0], -0.25);
+        // The gain recovers slowly after the peak.
+        assert!(outputs[0][1] > 0.1 && outputs[0][1] < 0.11);
+        assert_eq!(outputs[0][1], outputs[1][1] * 2.0);
+    }
+
+    #[test]
+    fn recovers_the_limiter_gain() {
+        let (_, mut protector) = protector(OutputProtection::default());
+        let mut outputs = vec![vec![2.0]];
+        protector.limit(&mut outputs, 1.0);
+        assert_eq!(protector.gain, 0.5);
+
+        let mut outputs = vec![vec![0.1; 1000]];
+        protector.limit(&mut outputs, 1.0);
+        assert!(protector.gain > 0.99);
+        assert!(outputs[0][999] > 0.099);
+    }
+
+    #[test]
+    fn never_exceeds_the_ceiling() {
+        let (_, mut protector) = protector(OutputProtection::default());
+        let mut outputs = vec![(0..100).map(|i| i as f32 * 0.1).collect::<Vec<_>>()];
+        protector.limit(&mut outputs, 0.8);
+
+        assert!(outputs[0].iter().all(|sample| sample.abs() <= 0.8));
+    }
+
+    #[test]
+    fn ignores_non_finite_ceilings() {
+        let protection = OutputProtection::default();
+
+        assert_eq!(protection.ceiling(Some(f32::NAN)).ceiling, None);
+        assert_eq!(protection.ceiling(Some(f32::INFINITY)).ceiling, None);
+        assert_eq!(protection.ceiling(Some(-0.5)).ceiling, Some(0.5));
+    }
+
+    #[test]
+    fn keeps_the_dc_blocker_stable_at_low_sample_rates() {
+        for sample_rate in [0, 1, 50] {
+            let (_, mut protector) = output_protection(OutputProtection::default(), sample_rate);
+            let mut outputs = vec![vec![0.5; 1000]];
+            protector.process(&mut outputs);
+
+            assert!(outputs[0].iter().all(|sample| sample.abs() <= 1.0));
+        }
+    }
+
+    #[test]
+    fn mutes_non_finite_blocks_and_reports_them_once() {
+        let (events, mut protector) = protector(OutputProtection::default());
+
+        for _ in 0..2 {
+            let mut outputs = vec![vec![0.5, f32::NAN]];
+            protector.process(&mut outputs);
+            assert_eq!(outputs[0], [0.0, 0.0]);
+        }
+
+        assert_eq!(events.take(), [ProtectionEvent::NonFinite]);
+    }
+}
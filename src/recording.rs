@@ -0,0 +1,286 @@
+//! Recording of the live output to WAV files.
+
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+use rtrb::{Consumer, Producer, RingBuffer};
+
+use crate::ShellError;
+
+/// Length of the audio buffered for the writer thread in seconds.
+const BUFFERED_SECONDS: usize = 2;
+
+/// Interval for writing the buffered samples to the file.
+const WRITE_INTERVAL: Duration = Duration::from_millis(10);
+
+/// Time the writer thread waits for the audio callback to release the buffer when stopped.
+const STOP_TIMEOUT: Duration = Duration::from_millis(500);
+
+/// Number of producer changes that can be queued for the audio callback.
+const TAP_QUEUE_SIZE: usize = 4;
+
+/// Sample format of recorded WAV files.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum RecordingFormat {
+    /// 16-bit integer samples.
+    Int16,
+
+    /// 24-bit integer samples.
+    Int24,
+
+    /// 32-bit float samples.
+    #[default]
+    Float32,
+}
+
+impl RecordingFormat {
+    /// Returns the WAV specification for stereo files in this format.
+    fn spec(self, sample_rate: u32) -> hound::WavSpec {
+        let (bits_per_sample, sample_format) = match self {
+            Self::Int16 => (16, hound::SampleFormat::Int),
+            Self::Int24 => (24, hound::SampleFormat::Int),
+            Self::Float32 => (32, hound::SampleFormat::Float),
+        };
+
+        hound::WavSpec {
+            channels: 2,
+            sample_rate,
+            bits_per_sample,
+            sample_format,
+        }
+    }
+}
+
+/// Creates the handles for controlling the recording and tapping the output in the audio
+/// callback.
+/// - `sample_rate` is the sampling frequency of the recorded files in Hz.
+pub(crate) fn recording(sample_rate: u32) -> (Recorder, RecordingTap) {
+    let (producers, consumer) = RingBuffer::new(TAP_QUEUE_SIZE);
+    let dropped_frames = Arc::new(AtomicUsize::new(0));
+
+    let recorder = Recorder {
+        sample_rate,
+        producers,
+        dropped_frames: dropped_frames.clone(),
+        writer: None,
+    };
+    let tap = RecordingTap {
+        producers: consumer,
+        producer: None,
+        dropped_frames,
+    };
+
+    (recorder, tap)
+}
+
+/// Thread writing the file of a recording.
+struct Writer {
+    /// The thread, returning the result of writing the file.
+    thread: JoinHandle<Result<(), hound::Error>>,
+
+    /// Flag signalling the thread to stop.
+    stop: Arc<AtomicBool>,
+
+    /// Flag set by the thread when writing failed.
+    failed: Arc<AtomicBool>,
+}
+
+/// Main thread side of the recording.
+pub(crate) struct Recorder {
+    /// Sampling frequency in Hz.
+    sample_rate: u32,
+
+    /// Producer for the sample buffers passed to the audio callback, `None` to stop recording.
+    producers: Producer<Option<Producer<f32>>>,
+
+    /// Number of frames the audio callback couldn't buffer.
+    dropped_frames: Arc<AtomicUsize>,
+
+    /// Writer of the running recording.
+    writer: Option<Writer>,
+}
+
+impl Recorder {
+    /// Returns if a recording is running and writing its file didn't fail.
+    pub fn is_recording(&self) -> bool {
+        self.writer
+            .as_ref()
+            .is_some_and(|writer| !writer.failed.load(Ordering::Acquire))
+    }
+
+    /// Creates the file at `path` and starts recording into it. A running recording is stopped
+    /// before.
+    pub fn start(&mut self, path: &Path, format: RecordingFormat) -> Result<(), ShellError> {
+        self.stop()?;
+
+        // Checked before creating the file, so no empty file is left behind.
+        if self.producers.is_full() {
+            return Err(ShellError::Recording("Too many pending requests".into()));
+        }
+
+        let error =
+            |error: hound::Error| ShellError::Recording(format!("{}: {}", path.display(), error));
+        let writer =
+            hound::WavWriter::create(path, format.spec(self.sample_rate)).map_err(error)?;
+
+        let (producer, consumer) =
+            RingBuffer::new(self.sample_rate as usize * 2 * BUFFERED_SECONDS);
+        // Can't fail, as the queue had a free slot and is only filled by this recorder.
+        self.producers.push(Some(producer)).ok();
+
+        self.dropped_frames.store(0, Ordering::Relaxed);
+        let stop = Arc::new(AtomicBool::new(false));
+        let failed = Arc::new(AtomicBool::new(false));
+        let thread = std::thread::spawn({
+            let stop = stop.clone();
+            let failed = failed.clone();
+            move || write_samples(writer, consumer, format, &stop, &failed)
+        });
+        self.writer = Some(Writer {
+            thread,
+            stop,
+            failed,
+        });
+
+        Ok(())
+    }
+
+    /// Stops the running recording and waits until the file is written.
+    /// Returns the error if writing the file failed. Does nothing if no recording is running.
+    pub fn stop(&mut self) -> Result<(), ShellError> {
+        let Some(Writer { thread, stop, .. }) = self.writer.take() else {
+            return Ok(());
+        };
+
+        // The audio callback drops its producer, which ends the writer thread. If the callback
+        // isn't running anymore, the writer gives up waiting after a timeout.
+        self.producers.push(None).ok();
+        stop.store(true, Ordering::Release);
+
+        let result = thread
+            .join()
+            .map_err(|_| ShellError::Recording("Writer thread panicked".into()))?;
+
+        let dropped_frames = self.dropped_frames.load(Ordering::Relaxed);
+        if dropped_frames > 0 {
+            log::warn!("Recording dropped {} frames", dropped_frames);
+        }
+
+        result.map_err(|error| ShellError::Recording(error.to_string()))
+    }
+}
+
+impl Drop for Recorder {
+    fn drop(&mut self) {
+        if let Err(error) = self.stop() {
+            log::error!("{}", error);
+        }
+    }
+}
+
+/// Writes the samples from `consumer` into `writer` until the producer is dropped, or until
+/// [`STOP_TIMEOUT`] after `stop` is set.
+///
+/// If writing fails, `failed` is set and the samples are discarded until then, so the buffer
+/// is still released by this thread rather than the audio callback.
+fn write_samples(
+    writer: hound::WavWriter<std::io::BufWriter<std::fs::File>>,
+    mut consumer: Consumer<f32>,
+    format: RecordingFormat,
+    stop: &AtomicBool,
+    failed: &AtomicBool,
+) -> Result<(), hound::Error> {
+    let mut writer = Some(writer);
+    let mut result = Ok(());
+    let mut stop_deadline = None;
+
+    loop {
+        // Checked before draining, so no samples pushed before dropping the producer are lost.
+        let abandoned = consumer.is_abandoned();
+
+        while let Ok(sample) = consumer.pop() {
+            let Some(file) = writer.as_mut() else {
+                continue;
+            };
+
+            let written = match format {
+                RecordingFormat::Int16 => {
+                    file.write_sample((sample.clamp(-1.0, 1.0) * i16::MAX as f32).round() as i16)
+                }
+                RecordingFormat::Int24 => {
+                    file.write_sample((sample.clamp(-1.0, 1.0) * 8_388_607.0).round() as i32)
+                }
+                RecordingFormat::Float32 => file.write_sample(sample),
+            };
+
+            if let Err(error) = written {
+                writer = None;
+                result = Err(error);
+                failed.store(true, Ordering::Release);
+            }
+        }
+
+        if abandoned {
+            break;
+        }
+
+        if stop.load(Ordering::Acquire) {
+            let deadline = *stop_deadline.get_or_insert_with(|| Instant::now() + STOP_TIMEOUT);
+            if Instant::now() > deadline {
+                break;
+            }
+        }
+
+        std::thread::sleep(WRITE_INTERVAL);
+    }
+
+    result.and_then(|()| writer.map_or(Ok(()), hound::WavWriter::finalize))
+}
+
+/// Tap passing the output of the audio callback to the running recording.
+pub(crate) struct RecordingTap {
+    /// Consumer for the sample buffers of new recordings.
+    producers: Consumer<Option<Producer<f32>>>,
+
+    /// Sample buffer of the running recording.
+    producer: Option<Producer<f32>>,
+
+    /// Number of frames that couldn't be buffered.
+    dropped_frames: Arc<AtomicUsize>,
+}
+
+impl RecordingTap {
+    /// Passes the first two channels of the interleaved device buffer to the running recording.
+    /// Mono output is recorded on both channels.
+    pub fn write(&mut self, data: &[f32], channels: usize) {
+        // Replacing the producer only drops a reference, the buffer is freed by the writer thread,
+        // unless the callback stalled for longer than the stop timeout.
+        while let Ok(producer) = self.producers.pop() {
+            self.producer = producer;
+        }
+
+        let Some(producer) = self.producer.as_mut() else {
+            return;
+        };
+
+        let mut dropped_frames = 0;
+        for frame in data.chunks(channels) {
+            if producer.slots() >= 2 {
+                let left = frame[0];
+                let right = frame.get(1).copied().unwrap_or(left);
+                producer.push(left).ok();
+                producer.push(right).ok();
+            } else {
+                dropped_frames += 1;
+            }
+        }
+
+        if dropped_frames > 0 {
+            self.dropped_frames
+                .fetch_add(dropped_frames, Ordering::Relaxed);
+        }
+    }
+}
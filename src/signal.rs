@@ -0,0 +1,44 @@
+//! Waiting for termination signals.
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::OnceLock;
+use std::time::Duration;
+
+use crate::ShellError;
+
+/// Interval for checking the interrupted flag.
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// Flag set by the signal handler.
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+
+/// Result of the handler installation, which can only be done once per process.
+static HANDLER: OnceLock<Result<(), String>> = OnceLock::new();
+
+/// Installs the handler for SIGINT and SIGTERM, or Ctrl-C on Windows, if not done yet.
+pub(crate) fn install_handler() -> Result<(), ShellError> {
+    HANDLER
+        .get_or_init(|| {
+            ctrlc::set_handler(|| INTERRUPTED.store(true, Ordering::Release))
+                .map_err(|error| error.to_string())
+        })
+        .clone()
+        .map_err(ShellError::Signal)
+}
+
+/// Returns if a signal was received since the last call.
+/// The handler must be installed with [`install_handler`] before.
+pub(crate) fn take_interrupt() -> bool {
+    INTERRUPTED.swap(false, Ordering::AcqRel)
+}
+
+/// Blocks until a signal is received.
+pub(crate) fn wait_for_interrupt() -> Result<(), ShellError> {
+    install_handler()?;
+
+    while !take_interrupt() {
+        std::thread::sleep(POLL_INTERVAL);
+    }
+
+    Ok(())
+}
@@ -0,0 +1,171 @@
+//! Load measurement of the audio callback.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Number of bins of the load histogram.
+pub const LOAD_HISTOGRAM_BINS: usize = 11;
+
+/// Load statistics of the audio callback.
+///
+/// The load is the time spent in the callback relative to the duration of the audio it writes to
+/// the device, so a load of `1.0` or more means the generator didn't finish in time, which is
+/// audible as a dropout.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct CallbackStats {
+    /// Number of measured device callbacks, which can each cover several or partial processor
+    /// blocks.
+    pub callbacks: u64,
+
+    /// Minimum load.
+    pub min_load: f32,
+
+    /// Average load.
+    pub avg_load: f32,
+
+    /// Maximum load.
+    pub max_load: f32,
+
+    /// Number of callbacks per load range. Bin `n` counts the callbacks with a load from `n * 0.1`
+    /// to below `(n + 1) * 0.1`, the last bin counts the overruns.
+    pub histogram: [u64; LOAD_HISTOGRAM_BINS],
+
+    /// Number of callbacks with a load of `1.0` or more.
+    pub overruns: u64,
+}
+
+/// Counters shared between the audio callback and the shell.
+#[derive(Debug)]
+struct Counters {
+    /// Number of measured callbacks.
+    callbacks: AtomicU64,
+
+    /// Sum of the processing times in nanoseconds.
+    total_time: AtomicU64,
+
+    /// Minimum processing time in nanoseconds.
+    min_time: AtomicU64,
+
+    /// Maximum processing time in nanoseconds.
+    max_time: AtomicU64,
+
+    /// Number of callbacks per load range.
+    histogram: [AtomicU64; LOAD_HISTOGRAM_BINS],
+}
+
+impl Counters {
+    /// Returns zeroed counters.
+    fn new() -> Self {
+        Self {
+            callbacks: AtomicU64::new(0),
+            total_time: AtomicU64::new(0),
+            min_time: AtomicU64::new(u64::MAX),
+            max_time: AtomicU64::new(0),
+            histogram: Default::default(),
+        }
+    }
+}
+
+/// Creates the handles for recording and reading the statistics.
+/// - `block_duration` is the real-time duration of a block.
+/// - `block_size` is the number of frames per block.
+pub(crate) fn callback_stats(
+    block_duration: Duration,
+    block_size: usize,
+) -> (StatsReader, StatsRecorder) {
+    let counters = Arc::new(Counters::new());
+    let block_time = (block_duration.as_nanos() as u64).max(1);
+
+    let reader = StatsReader {
+        counters: counters.clone(),
+        block_time,
+    };
+    let recorder = StatsRecorder {
+        counters,
+        block_time,
+        block_size,
+    };
+
+    (reader, recorder)
+}
+
+/// Handle for recording the processing times in the audio callback.
+pub(crate) struct StatsRecorder {
+    /// Shared counters.
+    counters: Arc<Counters>,
+
+    /// Duration of a block in nanoseconds.
+    block_time: u64,
+
+    /// Number of frames per block.
+    block_size: usize,
+}
+
+impl StatsRecorder {
+    /// Records the time spent in a callback.
+    /// - `frame_count` is the number of frames written by the callback. The time is scaled to
+    ///   the duration of a block, so callbacks of any size can be compared.
+    pub fn record(&self, elapsed: Duration, frame_count: usize) {
+        if frame_count == 0 {
+            return;
+        }
+
+        let time = (elapsed.as_nanos() * self.block_size as u128 / frame_count as u128) as u64;
+        let counters = &self.counters;
+
+        counters.callbacks.fetch_add(1, Ordering::Relaxed);
+        counters.total_time.fetch_add(time, Ordering::Relaxed);
+        counters.min_time.fetch_min(time, Ordering::Relaxed);
+        counters.max_time.fetch_max(time, Ordering::Relaxed);
+
+        let bin = (time * 10 / self.block_time).min(LOAD_HISTOGRAM_BINS as u64 - 1);
+        counters.histogram[bin as usize].fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// Handle for reading the statistics.
+pub(crate) struct StatsReader {
+    /// Shared counters.
+    counters: Arc<Counters>,
+
+    /// Duration of a block in nanoseconds.
+    block_time: u64,
+}
+
+impl StatsReader {
+    /// Returns the current statistics.
+    pub fn stats(&self) -> CallbackStats {
+        let counters = &self.counters;
+        let callbacks = counters.callbacks.load(Ordering::Relaxed);
+
+        if callbacks == 0 {
+            return CallbackStats::default();
+        }
+
+        let load = |time: u64| time as f32 / self.block_time as f32;
+        let histogram = std::array::from_fn(|n| counters.histogram[n].load(Ordering::Relaxed));
+
+        CallbackStats {
+            callbacks,
+            min_load: load(counters.min_time.load(Ordering::Relaxed)),
+            avg_load: load(counters.total_time.load(Ordering::Relaxed)) / callbacks as f32,
+            max_load: load(counters.max_time.load(Ordering::Relaxed)),
+            histogram,
+            overruns: histogram[LOAD_HISTOGRAM_BINS - 1],
+        }
+    }
+
+    /// Sets all counters back to zero.
+    pub fn reset(&self) {
+        let counters = &self.counters;
+
+        counters.callbacks.store(0, Ordering::Relaxed);
+        counters.total_time.store(0, Ordering::Relaxed);
+        counters.min_time.store(u64::MAX, Ordering::Relaxed);
+        counters.max_time.store(0, Ordering::Relaxed);
+        for bin in counters.histogram.iter() {
+            bin.store(0, Ordering::Relaxed);
+        }
+    }
+}
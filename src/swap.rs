@@ -0,0 +1,484 @@
+//! Replacement of the running processor with a crossfade.
+
+use std::any::Any;
+use std::sync::mpsc;
+use std::time::Duration;
+
+use rtrb::{Consumer, Producer, RingBuffer};
+
+use crate::engine::Engine;
+use crate::input::InputSource;
+use crate::{AudioProcessor, InitContext, MidiEvent, MidiMessage, MidiSender, Param};
+
+/// Number of processors that can be queued for swapping.
+const SWAP_QUEUE_SIZE: usize = 4;
+
+/// Number of replaced processors that can be queued for disposal.
+const RETIRED_QUEUE_SIZE: usize = 16;
+
+/// Converts the processor into the value handed back by the shell.
+pub(crate) type IntoAny<P> = fn(P) -> Box<dyn Any + Send>;
+
+/// Processor with its type erased, so processors of different types can be swapped.
+pub(crate) trait DynProcessor: Send {
+    /// Returns a reference to the processor.
+    fn processor(&self) -> &dyn AudioProcessor;
+
+    /// Returns a mutable reference to the processor.
+    fn processor_mut(&mut self) -> &mut dyn AudioProcessor;
+
+    /// Converts the processor into the value handed back by the shell.
+    fn into_any(self: Box<Self>) -> Box<dyn Any + Send>;
+}
+
+/// Boxed processor of any type.
+pub(crate) type BoxedProcessor = Box<dyn DynProcessor>;
+
+/// Engine running a boxed processor.
+pub(crate) type DynEngine = Engine<BoxedProcessor>;
+
+/// Processor together with its conversion.
+struct Erased<P> {
+    /// The processor.
+    processor: P,
+
+    /// Conversion into the value handed back by the shell.
+    into_any: IntoAny<P>,
+}
+
+impl<P: AudioProcessor + Send + 'static> DynProcessor for Erased<P> {
+    fn processor(&self) -> &dyn AudioProcessor {
+        &self.processor
+    }
+
+    fn processor_mut(&mut self) -> &mut dyn AudioProcessor {
+        &mut self.processor
+    }
+
+    fn into_any(self: Box<Self>) -> Box<dyn Any + Send> {
+        (self.into_any)(self.processor)
+    }
+}
+
+/// Returns the boxed processor, handed back converted by `into_any`.
+pub(crate) fn boxed<P: AudioProcessor + Send + 'static>(
+    processor: P,
+    into_any: IntoAny<P>,
+) -> BoxedProcessor {
+    Box::new(Erased {
+        processor,
+        into_any,
+    })
+}
+
+impl AudioProcessor for BoxedProcessor {
+    fn init(&mut self, block_size: usize) {
+        self.processor_mut().init(block_size);
+    }
+
+    fn init_with_context(&mut self, context: &InitContext) {
+        self.processor_mut().init_with_context(context);
+    }
+
+    fn process(&mut self, inputs: &[&[f32]], outputs: &mut [&mut [f32]]) {
+        self.processor_mut().process(inputs, outputs);
+    }
+
+    fn process_midi(&mut self, message: &[u8]) {
+        self.processor_mut().process_midi(message);
+    }
+
+    fn process_midi_message(&mut self, message: MidiMessage) {
+        self.processor_mut().process_midi_message(message);
+    }
+
+    fn process_midi_event(&mut self, event: MidiEvent) {
+        self.processor_mut().process_midi_event(event);
+    }
+
+    fn init_midi_output(&mut self, sender: MidiSender) {
+        self.processor_mut().init_midi_output(sender);
+    }
+
+    fn params(&self) -> Vec<Param> {
+        self.processor().params()
+    }
+
+    fn deinit(&mut self) {
+        self.processor_mut().deinit();
+    }
+
+    fn min_output_channels(&self) -> usize {
+        self.processor().min_output_channels()
+    }
+}
+
+/// New engine together with the crossfade length in frames.
+type Swap = (DynEngine, usize);
+
+/// Settings for creating the engines of swapped processors.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct EngineConfig {
+    /// Settings passed to the processors on initialization.
+    pub context: InitContext,
+
+    /// Number of MIDI events that can be queued for output.
+    pub midi_queue_size: usize,
+
+    /// Number of commands that can be queued for the processor.
+    pub command_queue_size: usize,
+
+    /// Flag for splitting the blocks at the frame offsets of MIDI events.
+    pub split_at_midi_events: bool,
+}
+
+/// Creates the handles for swapping the engine of the audio callback.
+/// - `engine` is the initially running engine.
+/// - `returned` receives the processor when the audio callback is dropped.
+/// - `config` is used for creating the engines of swapped processors.
+pub(crate) fn engine_switch(
+    engine: DynEngine,
+    returned: mpsc::Sender<Box<dyn Any + Send>>,
+    config: EngineConfig,
+) -> (SwapSender, EngineSwitch) {
+    let (swap_producer, swap_consumer) = RingBuffer::new(SWAP_QUEUE_SIZE);
+    let (retired_producer, retired_consumer) = RingBuffer::new(RETIRED_QUEUE_SIZE);
+
+    let sender = SwapSender {
+        swaps: swap_producer,
+        retired: retired_consumer,
+        config,
+    };
+    let switch = EngineSwitch {
+        current: Some(engine),
+        next: None,
+        fade_length: 1,
+        fade_position: 0,
+        swaps: swap_consumer,
+        retired: retired_producer,
+        returned,
+    };
+
+    (sender, switch)
+}
+
+/// Main thread side of the engine swapping.
+pub(crate) struct SwapSender {
+    /// Producer for the new engines.
+    swaps: Producer<Swap>,
+
+    /// Consumer for the replaced engines.
+    retired: Consumer<DynEngine>,
+
+    /// Settings for the new engines.
+    config: EngineConfig,
+}
+
+impl SwapSender {
+    /// Returns if no more engines can be queued.
+    pub fn is_full(&self) -> bool {
+        self.swaps.is_full()
+    }
+
+    /// Returns the settings for the new engines.
+    pub fn config(&self) -> EngineConfig {
+        self.config
+    }
+
+    /// Initializes `processor` and queues it to replace the running one, crossfading over
+    /// `crossfade`. Returns the parameters declared by the processor.
+    /// - `midi_sender` is passed to the processor for sending MIDI messages.
+    ///
+    /// The queue must be checked with `is_full` before.
+    pub fn send(
+        &mut self,
+        processor: BoxedProcessor,
+        midi_sender: MidiSender,
+        crossfade: Duration,
+    ) -> Vec<Param> {
+        self.dispose_retired();
+
+        let mut engine = Engine::new(processor, &self.config.context, midi_sender);
+        if self.config.split_at_midi_events {
+            engine.split_at_midi_events(self.config.midi_queue_size);
+        }
+        let params = engine.params().to_vec();
+        let fade_length =
+            (crossfade.as_secs_f64() * self.config.context.sample_rate as f64) as usize;
+
+        if let Err(rtrb::PushError::Full((engine, _))) = self.swaps.push((engine, fade_length)) {
+            engine.into_processor();
+        }
+
+        params
+    }
+
+    /// Deinitializes and drops the replaced engines.
+    pub fn dispose_retired(&mut self) {
+        while let Ok(engine) = self.retired.pop() {
+            engine.into_processor();
+        }
+    }
+}
+
+/// Audio callback side of the engine swapping.
+/// When dropped, the running processor is deinitialized and handed back to the shell.
+pub(crate) struct EngineSwitch {
+    /// Running engine, only taken when dropped.
+    current: Option<DynEngine>,
+
+    /// Engine faded in.
+    next: Option<DynEngine>,
+
+    /// Length of the running crossfade in frames.
+    fade_length: usize,
+
+    /// Position in the running crossfade in frames.
+    fade_position: usize,
+
+    /// Consumer for the new engines.
+    swaps: Consumer<Swap>,
+
+    /// Producer for the replaced engines.
+    retired: Producer<DynEngine>,
+
+    /// Sender for the processor when dropped.
+    returned: mpsc::Sender<Box<dyn Any + Send>>,
+}
+
+impl EngineSwitch {
+    /// Starts a queued crossfade. Must be called at the start of each block.
+    ///
+    /// The crossfade is postponed while the queue for the replaced engines is full, so they are
+    /// never dropped in the audio callback.
+    pub fn begin_block(&mut self) {
+        if self.next.is_none() && !self.retired.is_full() {
+            if let Ok((engine, fade_length)) = self.swaps.pop() {
+                self.next = Some(engine);
+                self.fade_length = fade_length.max(1);
+                self.fade_position = 0;
+            }
+        }
+    }
+
+    /// Passes a MIDI event to the running engines.
+    pub fn process_midi_event(&mut self, event: MidiEvent) {
+        self.current_mut().process_midi_event(event);
+        if let Some(next) = self.next.as_mut() {
+            next.process_midi_event(event);
+        }
+    }
+
+    /// Reads the input buffers of the running engines from `source`.
+    pub fn read_input(&mut self, source: &mut InputSource) {
+        source.read(self.current_mut().inputs_mut());
+
+        if let (Some(current), Some(next)) = (self.current.as_ref(), self.next.as_mut()) {
+            for (input, buffer) in next.inputs_mut().iter_mut().zip(current.inputs()) {
+                input.copy_from_slice(buffer);
+            }
+        }
+    }
+
+    /// Processes a block with the running engines and crossfades their outputs.
+    pub fn process(&mut self) {
+        self.current_mut().process();
+
+        let Some(next) = self.next.as_mut() else {
+            return;
+        };
+        next.process();
+
+        let Some(current) = self.current.as_mut() else {
+            return;
+        };
+
+        let mut frame_count = 0;
+        for (output, next_output) in current.outputs_mut().iter_mut().zip(next.outputs()) {
+            frame_count = output.len();
+            for (frame_no, (sample, next_sample)) in
+                output.iter_mut().zip(next_output.iter()).enumerate()
+            {
+                let position = (self.fade_position + frame_no) as f32 / self.fade_length as f32;
+                let position = position.min(1.0);
+                *sample = *sample * (1.0 - position) + next_sample * position;
+            }
+        }
+
+        self.fade_position += frame_count;
+
+        if self.fade_position >= self.fade_length {
+            if let (Some(current), Some(next)) = (self.current.take(), self.next.take()) {
+                self.current = Some(next);
+
+                // Can't fail, as the crossfade only starts with room in the queue.
+                self.retired.push(current).ok();
+            }
+        }
+    }
+
+    /// Returns the output buffers of the last processed block, one per channel.
+    pub fn outputs(&self) -> &[Vec<f32>] {
+        self.current
+            .as_ref()
+            .map(|engine| engine.outputs())
+            .unwrap_or_default()
+    }
+
+    /// Returns the output buffers of the last processed block for modification.
+    pub fn outputs_mut(&mut self) -> &mut [Vec<f32>] {
+        self.current_mut().outputs_mut()
+    }
+
+    /// Returns the running engine.
+    fn current_mut(&mut self) -> &mut DynEngine {
+        self.current.as_mut().expect("Engine already dropped")
+    }
+}
+
+impl Drop for EngineSwitch {
+    /// Completes the running and queued swaps, so the newest processor is handed back.
+    fn drop(&mut self) {
+        let mut engines: Vec<_> = self
+            .current
+            .take()
+            .into_iter()
+            .chain(self.next.take())
+            .collect();
+        while let Ok((engine, _)) = self.swaps.pop() {
+            engines.push(engine);
+        }
+
+        let newest = engines.pop();
+        for engine in engines {
+            engine.into_processor();
+        }
+
+        if let Some(engine) = newest {
+            self.returned.send(engine.into_processor().into_any()).ok();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use super::*;
+    use crate::midi::midi_output_queue;
+
+    /// Numbers of the deinitialized processors.
+    type Deinits = Arc<Mutex<Vec<u32>>>;
+
+    /// Receiver for the processor handed back by the switch.
+    type Returned = mpsc::Receiver<Box<dyn Any + Send>>;
+
+    /// Processor logging its deinitialization.
+    struct Probe {
+        /// Number identifying the processor.
+        id: u32,
+
+        /// Numbers of the deinitialized processors.
+        deinits: Deinits,
+    }
+
+    impl AudioProcessor for Probe {
+        fn process(&mut self, _: &[&[f32]], _: &mut [&mut [f32]]) {}
+
+        fn deinit(&mut self) {
+            self.deinits.lock().unwrap().push(self.id);
+        }
+    }
+
+    /// Returns the switch running processor `0` with 64 frames per block, together with the
+    /// handles for swapping, receiving the returned processor and the logged deinitializations.
+    fn switch() -> (SwapSender, EngineSwitch, Returned, Deinits) {
+        let config = EngineConfig {
+            context: InitContext::new(1000, 64, 2, 0),
+            midi_queue_size: 16,
+            command_queue_size: 16,
+            split_at_midi_events: false,
+        };
+        let deinits = Arc::new(Mutex::new(Vec::new()));
+        let (midi_sender, _) = midi_output_queue(16);
+        let engine = Engine::new(probe(0, &deinits), &config.context, midi_sender);
+        let (returned_sender, returned) = mpsc::channel();
+        let (sender, switch) = engine_switch(engine, returned_sender, config);
+
+        (sender, switch, returned, deinits)
+    }
+
+    /// Returns the boxed processor with the number `id`.
+    fn probe(id: u32, deinits: &Deinits) -> BoxedProcessor {
+        let probe = Probe {
+            id,
+            deinits: deinits.clone(),
+        };
+        boxed(probe, |probe| Box::new(probe))
+    }
+
+    /// Queues the processor with the number `id`.
+    fn send(sender: &mut SwapSender, id: u32, deinits: &Deinits, crossfade: u64) {
+        let (midi_sender, _) = midi_output_queue(16);
+        sender.send(
+            probe(id, deinits),
+            midi_sender,
+            Duration::from_millis(crossfade),
+        );
+    }
+
+    /// Returns the number of the processor handed back by the switch.
+    fn returned_id(returned: &Returned) -> u32 {
+        returned.try_recv().unwrap().downcast::<Probe>().unwrap().id
+    }
+
+    #[test]
+    fn returns_the_new_processor_when_stopped_during_the_crossfade() {
+        let (mut sender, mut switch, returned, deinits) = switch();
+        send(&mut sender, 1, &deinits, 1000);
+        switch.begin_block();
+        switch.process();
+        drop(switch);
+
+        assert_eq!(returned_id(&returned), 1);
+        assert_eq!(*deinits.lock().unwrap(), [0, 1]);
+    }
+
+    #[test]
+    fn returns_the_newest_queued_processor_when_stopped() {
+        let (mut sender, switch, returned, deinits) = switch();
+        send(&mut sender, 1, &deinits, 0);
+        send(&mut sender, 2, &deinits, 0);
+        drop(switch);
+
+        assert_eq!(returned_id(&returned), 2);
+        assert_eq!(*deinits.lock().unwrap(), [0, 1, 2]);
+    }
+
+    #[test]
+    fn postpones_swaps_while_the_retired_queue_is_full() {
+        let (mut sender, mut switch, returned, deinits) = switch();
+
+        for id in 1..=RETIRED_QUEUE_SIZE as u32 + 1 {
+            // Queued directly, as sending disposes the retired engines.
+            let (midi_sender, _) = midi_output_queue(16);
+            let context = sender.config().context;
+            let engine = Engine::new(probe(id, &deinits), &context, midi_sender);
+            sender.swaps.push((engine, 0)).ok();
+
+            switch.begin_block();
+            switch.process();
+        }
+
+        assert!(switch.next.is_none());
+        assert_eq!(switch.swaps.slots(), 1);
+        assert!(deinits.lock().unwrap().is_empty());
+
+        sender.dispose_retired();
+        switch.begin_block();
+        switch.process();
+        drop(switch);
+
+        assert_eq!(returned_id(&returned), RETIRED_QUEUE_SIZE as u32 + 1);
+    }
+}